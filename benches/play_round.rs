@@ -0,0 +1,9 @@
+use evolutionary_sim::simulation::test::play_round_benchmark;
+
+/// cargo bench --bench play_round, times the noisy rounds of fixed populations of 100+ genotypes
+/// on 1 thread and on the default thread pool
+fn main() {
+   play_round_benchmark(128, 2, 20);
+   play_round_benchmark(256, 2, 5);
+   play_round_benchmark(100, 3, 2);
+}
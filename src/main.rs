@@ -1,18 +1,8 @@
-mod simulation;
-use polars::frame::DataFrame;
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
-use crate::simulation::{agent::Agent, game::Game, test::{mega_test, run_test}, types::GameBoard};
-use std::{borrow::BorrowMut, time};
+use evolutionary_sim::simulation::{agent::Agent, game::Game, types::GameBoard};
 
 fn main() {
 
-   let t0 = time::Instant::now();
-   
-   let agents: Vec<Agent> = (0..1000)
-      .map(|_| Agent::random_init(2))
-      .collect();
-
-   let board = match simulation::types::GameBoard::new(
+   let board = match GameBoard::new(
       "prisoners_dilemma".to_string(),
       3
    ) {
@@ -30,16 +20,19 @@ fn main() {
       Ok(game) => game,
       Err(e) => panic!("Error creating game: {}", e),
    }; 
-   game.run(1000, agents);
+   game.seed = 42;
+   game.keep_rounds = false;
 
-   let mut df = match game.round_state_to_dataframe() {
-      Ok(df) => df,
-      Err(e) => panic!("Error creating dataframe: {}", e),
-   };
+   if let Err(e) = game.stream_to_parquet("test.parquet", 100) {
+      panic!("Error creating parquet sink: {}", e);
+   }
 
-   match game.dump_to_parquet(&mut df, "test.parquet".to_string()) {
-      Ok(_) => (),
-      Err(e) => panic!("Error dumping to parquet: {}", e),
+   let agents: Vec<Agent> = match game.random_agents(1000, 2) {
+      Ok(agents) => agents,
+      Err(e) => panic!("Error creating agents: {}", e),
+   };
+   if let Err(e) = game.run(1000, agents) {
+      panic!("Error running game: {}", e);
    }
 
 }
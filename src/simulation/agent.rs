@@ -1,191 +1,237 @@
 use rand::Rng;
 use serde::{Serialize, Deserialize};
-use serde::ser::{SerializeMap, Serializer, SerializeStruct};
-use rand_distr::{Distribution, Poisson};
-use std::cmp::Ordering;
-
-#[derive(Debug, Hash, PartialOrd, Deserialize)]
+use serde::ser::{Serializer, SerializeStruct};
+use crate::simulation::{
+    error::SimError,
+    genotype::{deserialize_actions, Genotype, MutationKind, SERIALIZED_FIELDS},
+    types::Action,
+};
+
+/// one player of a genotype, the genotype is what is inherited and counted in the population maps,
+/// the history is what this player remembers of its own games
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "SerializedAgent")]
 pub struct Agent {
     // attributes: memory, strategy, fitness
-    pub id : String,
-    pub memory_len : usize, // m
-    pub history: Vec<bool>, // length m [a_m-1, a_m-2, ...,a_1, a_0]
-    pub history_len : usize, // we maintain the length of the history to avoid recomputation
+    pub genotype : Genotype, // genome, memory length m and lineage
+    history: usize, // the history [a_m-1, a_m-2, ...,a_1, a_0] read as a base k number, the gene of the next action
+    pub history_len : usize, // the number of actions remembered, at most m
     //where a_0 is the opponent's last action, a_1 is the agent's last action, and so on
-    pub genome: Vec<bool>, // the strategy length n = 2^m [b_n-1, b_n-2, ...,b_1, b_0] the genome
 }
 
+/// the json layout of an Agent, the history is a list of actions
+#[derive(Deserialize)]
+struct SerializedAgent {
+    #[serde(flatten)]
+    genotype : Genotype,
+    // genotypes written without a history start from the empty one
+    #[serde(default, deserialize_with = "deserialize_actions")]
+    history : Vec<Action>,
+}
+
+impl TryFrom<SerializedAgent> for Agent {
+    type Error = SimError;
+
+    fn try_from(serialized : SerializedAgent) -> Result<Agent, SimError> {
+        Agent::check_history(&serialized.genotype, &serialized.history)?;
+        let mut agent = Agent::from_genotype(serialized.genotype);
+        agent.set_history(&serialized.history);
+        Ok(agent)
+    }
+}
+
+// the json keeps the flat layout of the agents written before genotypes had their own type
 impl Serialize for Agent {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("Agent", 5)?;
-        state.serialize_field("id", &self.id)?;
-        state.serialize_field("memory_len", &self.memory_len)?;
-        state.serialize_field("history", &self.history)?;
+        let mut state = serializer.serialize_struct("Agent", SERIALIZED_FIELDS + 2)?;
+        self.genotype.serialize_fields(&mut state)?;
+        state.serialize_field("history", &self.history())?;
         state.serialize_field("history_len", &self.history_len)?;
-        state.serialize_field("genome", &self.genome)?;
         state.end()
     }
 }
 
-
-impl Clone for Agent {
-    fn clone(&self) -> Agent {
-        Agent {
-            id: self.id.clone(),
-            memory_len: self.memory_len,
-            history: self.history.clone(),
-            history_len: self.history_len,
-            genome: self.genome.clone(),
-        }
+/// with probability error one of the other n_actions - 1 actions is played or seen instead of action,
+/// used by the agents and by the iterated games that only copy the history registers of the agents
+pub(crate) fn tremble<R: Rng + ?Sized>(action : Action, n_actions : usize, error : f64, rng : &mut R) -> Action {
+    // with a single action there is nothing else to play
+    if n_actions > 1 && error > 0.0 && rng.gen::<f64>() < error {
+        let shift = rng.gen_range(1..n_actions) as Action;
+        (action + shift) % n_actions as Action
+    } else {
+        action
     }
 }
 
-impl PartialEq for Agent {
-    fn eq(&self, other: &Self) -> bool {
-        self.genome == other.genome
+impl Agent {
+    /// random agent for a 2 action game
+    pub fn random_init(memory_len : u32) -> Result<Agent, SimError> {
+        Agent::random_init_with_actions(memory_len, 2)
     }
-}
 
-impl Eq for Agent {}
+    /// random agent over an alphabet of n_actions, the genome has n_actions^memory_len entries
+    pub fn random_init_with_actions(memory_len : u32, n_actions : u32) -> Result<Agent, SimError> {
+        Agent::random_init_from_rng(memory_len, n_actions, &mut rand::thread_rng())
+    }
 
-impl Ord for Agent {
-    //we otder by id
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.id.cmp(&other.id)
+    /// same as random_init_with_actions but drawing from the given rng, used for seeded runs
+    pub fn random_init_from_rng<R: Rng + ?Sized>(memory_len : u32, n_actions : u32, rng : &mut R) -> Result<Agent, SimError> {
+        Genotype::check_n_actions(n_actions as usize)?;
+        let history : Vec<Action> = (0..memory_len).map(|_| rng.gen_range(0..n_actions) as Action).collect();
+        let mut agent = Agent::from_genotype(Genotype::random_from_rng(memory_len, n_actions, rng)?);
+        agent.set_history(&history);
+        Ok(agent)
     }
-}
 
-impl Agent {
-    pub fn random_init(memory_len : u32) -> Agent {
-        let history = (0..memory_len).map(|_| rand::random::<bool>()).collect();
-        let genome : Vec<bool> = (0..2usize.pow(memory_len)).map(|_| rand::random::<bool>()).collect();
+    /// a player of the genotype that has not played yet, its history is empty
+    pub fn from_genotype(genotype : Genotype) -> Agent {
         Agent {
-            id : Agent::genome_to_id(genome.clone()),
-            memory_len : memory_len as usize,
-            history: history,
-            history_len: memory_len as usize,
-            genome: genome,
+            genotype,
+            history: 0,
+            history_len: 0,
         }
     }
 
-    
+    /// the history must have at most memory_len actions, all below k
+    fn check_history(genotype : &Genotype, history : &[Action]) -> Result<(), SimError> {
+        if history.len() > genotype.memory_len {
+            return Err(SimError::GenomeMismatch(format!(
+                "History longer than memory got history len {} and memory len {}", history.len(), genotype.memory_len
+            )));
+        }
+        if let Some(&action) = history.iter().find(|&&a| a as usize >= genotype.n_actions) {
+            return Err(SimError::GenomeMismatch(
+                format!("Invalid action {} expected an action below {}", action, genotype.n_actions)
+            ));
+        }
+        Ok(())
+    }
 
-    fn check_genome(genome : &Vec<bool>, history : &Vec<bool>) -> bool {
-        if genome.len() != 2usize.pow(history.len() as u32) {
-            panic!(
-                "Strategy and memory length mismatch got genome len {} and history len {} 
-                expected genome len of 2^history len {}", 
-                genome.len(), history.len(), 2usize.pow(history.len() as u32)
-            );
-        } 
-        return true;
+    pub fn from(genome : Vec<Action>, history : Vec<Action>, memory_len : u32, n_actions : u32) -> Result<Agent, SimError> {
+        Agent::new(genome, history, memory_len, n_actions)
     }
 
-    pub fn from(genome : Vec<bool>, history : Vec<bool>, memory_len : u32) -> Agent {
-        Agent::check_genome(&genome, &history);
-        Agent {
-            id : Agent::genome_to_id(genome.clone()),
-            memory_len : memory_len as usize,
-            history: history,
-            history_len: memory_len as usize,
-            genome: genome,
-        }
+    pub fn to_json(&self) -> Result<String, SimError> {
+        Ok(serde_json::to_string(&self)?)
     }
 
-    pub fn genome_to_id(genome : Vec<bool>) -> String {
-        let mut id = String::new();
-        for (i, &bit) in genome.iter().enumerate() {
-           if bit {
-              id.push('1');
-           } else {
-              id.push('0');
-           }
-        }
-        return id;
-     }
+    pub fn new(genome : Vec<Action>, history : Vec<Action>, memory_len : u32, n_actions : u32) -> Result<Agent, SimError> {
+        let genotype = Genotype::new(genome, memory_len, n_actions)?;
+        Agent::check_history(&genotype, &history)?;
 
-    pub fn to_json(&self) -> String {
-        return serde_json::to_string(&self).unwrap()
+        let mut agent = Agent::from_genotype(genotype);
+        agent.set_history(&history);
+        Ok(agent)
     }
 
-    pub fn new(genome : Vec<bool>, history : Vec<bool>, memory_len : u32) -> Agent {
-        if genome.len() != 2usize.pow(history.len() as u32) {
-            panic!(
-                "Strategy and memory length mismatch got genome len {} and history len {} 
-                expected genome len of 2^history len {}", 
-                genome.len(), history.len(), 2usize.pow(history.len() as u32)
-            );
+    /// the remembered actions, oldest first
+    pub fn history(&self) -> Vec<Action> {
+        let k = self.genotype.n_actions;
+        let mut history = vec![0; self.history_len];
+        let mut rest = self.history;
+        for slot in history.iter_mut().rev() {
+            *slot = (rest % k) as Action;
+            rest /= k;
         }
+        history
+    }
 
-        let len = history.len();
+    /// the history read as a base k number, the index of the gene get_action plays
+    pub fn history_index(&self) -> usize {
+        self.history
+    }
 
-        Agent {
-            id : Agent::genome_to_id(genome.clone()),
-            memory_len : memory_len as usize,
-            history: history,
-            history_len: len,
-            genome: genome,
-        }
+    /// replaces the history, the oldest actions that do not fit in the memory are dropped
+    pub fn set_history(&mut self, history : &[Action]) {
+        self.history = 0;
+        self.history_len = 0;
+        self.add_memory(history);
     }
 
-    pub fn add_memory(&mut self, old_actions : [bool; 2]) {
-        //we add the action pair from the last round to the memory    
-        // we pop the oldest action pair and push the new one 
-        if self.memory_len > self.history_len {
-            self.history.extend(old_actions.iter());
-            self.history_len += 2;
-        } else {
-            self.history.drain(0..2);
-            self.history.extend(old_actions.iter());
-        }
+    /// sets the history from its index, the history is full so history_len is the memory length
+    pub fn set_history_index(&mut self, history_index : usize) {
+        self.history = history_index % self.genotype.genome.len();
+        self.history_len = self.genotype.memory_len;
     }
 
-    pub fn get_action(&self) -> bool {
-        //given current history, return the action
-        let idx = self.history.iter().fold(0, |acc, &bit| (acc << 1) | (bit as u32));
-        let action = self.genome[idx as usize];
-        return action;
+    /// the agent of the behaviourally equivalent genotype with the shortest memory,
+    /// the oldest actions of the history are forgotten with the duplications, see Genotype::canonical
+    pub fn canonical(&self) -> Agent {
+        let mut agent = Agent {
+            genotype: self.genotype.canonical(),
+            history: self.history,
+            history_len: self.history_len,
+        };
+        agent.forget_beyond_memory();
+        agent
     }
 
-    pub fn mutate(&mut self,  p_p : f64, p_d : f64, p_s : f64) -> bool {
+    /// adds the actions of the last round to the memory, the agent's own action first and then the
+    /// opponents' actions, so for 2 players a_1 is the agent's last action and a_0 the opponent's.
+    /// The oldest actions are dropped so the history never grows past memory_len,
+    /// the history is a register shifted by one base k digit per action
+    pub fn add_memory(&mut self, old_actions : &[Action]) {
+        self.history = self.genotype.push_history(self.history, old_actions);
+        self.history_len = (self.history_len + old_actions.len()).min(self.genotype.memory_len);
+    }
 
-        let mut rng = rand::thread_rng();
-        //sample 3 one for each mutation type
-        let mut mutated = false;
+    /// drops the oldest actions that no longer fit in the memory of the genotype
+    fn forget_beyond_memory(&mut self) {
+        self.history %= self.genotype.genome.len();
+        self.history_len = self.history_len.min(self.genotype.memory_len);
+    }
 
-        let poisson = Poisson::new(p_p as f64).unwrap();
-        let PointMutation_outcome : bool = poisson.sample(&mut rng) > 0.0;
-         
-        if PointMutation_outcome {
-            println!("Point Mutation");
-            let index = rand::random::<usize>() % self.genome.len();
-            self.genome[index] = !self.genome[index]; 
-            mutated = true;
+    /// adds the last round to the memory like add_memory, but every opponent action is misperceived
+    /// with probability perception_error, the agent always knows its own action which comes first
+    pub fn perceive<R: Rng + ?Sized>(&mut self, old_actions : &[Action], perception_error : f64, rng : &mut R) {
+        if perception_error <= 0.0 {
+            self.add_memory(old_actions);
+            return;
         }
+        let perceived : Vec<Action> = old_actions.iter().enumerate()
+            .map(|(i, &action)| if i == 0 { action } else { self.tremble(action, perception_error, rng) })
+            .collect();
+        self.add_memory(&perceived);
+    }
 
-        /* let poisson = Poisson::new(p_d as f64).unwrap();
-        let GeneDuplication_outcome = poisson.sample(&mut rng) > 0.0;
-        
-        if GeneDuplication_outcome {
-            //attach a copy to the genome/S to itself
-            self.genome.extend(self.genome.clone());
-        } */
-
-        let poisson = Poisson::new(p_s as f64).unwrap();
-        let SplitMutation_outcome = poisson.sample(&mut rng) > 0.0;
-
-        if SplitMutation_outcome {
-            println!("Split Mutation");
-            let half = self.genome.len() / 2;
-            let start = if rand::thread_rng().gen() { 0 } else { half };
-            let selected_half = self.genome[start..start + half].to_vec();
-            self.genome.extend_from_slice(&selected_half);
-            mutated = true;
-        }
-        return mutated;
+    /// the action of the genome with an execution error, with probability execution_error one of
+    /// the other k - 1 actions is played instead
+    pub fn get_action_noisy<R: Rng + ?Sized>(&self, execution_error : f64, rng : &mut R) -> Action {
+        self.tremble(self.get_action(), execution_error, rng)
+    }
+
+    fn tremble<R: Rng + ?Sized>(&self, action : Action, error : f64, rng : &mut R) -> Action {
+        tremble(action, self.genotype.n_actions, error, rng)
+    }
+
+    pub fn get_action(&self) -> Action {
+        //given current history, return the action, the history is read as a base k number
+        //a history shorter than the memory reads the missing oldest actions as 0
+        self.genotype.action(self.history)
+    }
+
+    /// mutates the genotype, see Genotype::mutate, a split also forgets the oldest action of the history
+    pub fn mutate<R: Rng + ?Sized>(&mut self,  p_p : f64, p_d : f64, p_s : f64, rng : &mut R) -> Vec<MutationKind> {
+        let applied = self.genotype.mutate(p_p, p_d, p_s, rng);
+        self.forget_beyond_memory();
+        applied
+    }
+
+    /// applies one mutation to the genotype, see Genotype::apply_mutation
+    pub fn apply_mutation<R: Rng + ?Sized>(&mut self, kind : MutationKind, rng : &mut R) -> bool {
+        let applied = self.genotype.apply_mutation(kind, rng);
+        self.forget_beyond_memory();
+        applied
+    }
+
+    /// checks that the genome still has k^memory_len genes and the history fits in the memory
+    pub fn is_consistent(&self) -> bool {
+        self.genotype.is_consistent()
+            && self.history_len <= self.genotype.memory_len
+            && self.history < self.genotype.genome.len()
     }
 
 }
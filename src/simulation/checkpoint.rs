@@ -0,0 +1,74 @@
+use serde::{Serialize, Deserialize};
+use std::{fs::File, io::{BufReader, BufWriter, Write}};
+use crate::simulation::{
+    agent::Agent,
+    error::SimError,
+    game::Game,
+    types::AgentMetaData,
+};
+
+/// everything needed to continue a run bit identically.
+/// There is no generator state to store, every stream is derived from game.seed and the round number
+/// so the seed and next_round are the rng state.
+/// # Variables:
+///     pub game: Game - the parameters, the master seed and the rounds played so far
+///     pub population: Vec<(Agent, AgentMetaData)> - the population that plays next_round sorted by genotype,
+///         every genotype with the history its games start from, a list because agents can not be json map keys
+///     pub genotypes: Vec<Agent> - the genotypes of the registry with their history in handle order: the population
+///         and, when the game records a genealogy, the extinct genotypes of the tree. A genotype of the tree that
+///         arises again after the checkpoint keeps the lineage and the history it had before, as in an uninterrupted run
+///     pub next_round: u32 - the index of the first round that has not been played
+///     pub n_rounds: u32 - the total number of rounds of the run
+///     pub n_agents: u32 - the population size the run was started with
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Checkpoint {
+    pub game : Game,
+    pub population : Vec<(Agent, AgentMetaData)>,
+    #[serde(default)]
+    pub genotypes : Vec<Agent>,
+    pub next_round : u32,
+    pub n_rounds : u32,
+    pub n_agents : u32,
+}
+
+/// a Checkpoint that borrows the game, Game::run writes it without cloning the rounds kept in memory
+#[derive(Serialize)]
+pub(crate) struct CheckpointRef<'a> {
+    pub game : &'a Game,
+    pub population : Vec<(Agent, AgentMetaData)>,
+    pub genotypes : Vec<Agent>,
+    pub next_round : u32,
+    pub n_rounds : u32,
+    pub n_agents : u32,
+}
+
+impl CheckpointRef<'_> {
+    /// writes the json read by Checkpoint::load, see save_json
+    pub fn save(&self, file_path : &str) -> Result<(), SimError> {
+        save_json(self, file_path)
+    }
+}
+
+/// writes value to a temporary file next to file_path and renames it,
+/// so a crash while writing never leaves a truncated checkpoint behind
+fn save_json<T: Serialize>(value : &T, file_path : &str) -> Result<(), SimError> {
+    let temp_path = format!("{}.tmp", file_path);
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+    serde_json::to_writer(&mut writer, value)?;
+    writer.flush()?;
+    drop(writer);
+    std::fs::rename(&temp_path, file_path)?;
+    Ok(())
+}
+
+impl Checkpoint {
+    /// writes the checkpoint atomically, see save_json
+    pub fn save(&self, file_path : &str) -> Result<(), SimError> {
+        save_json(self, file_path)
+    }
+
+    pub fn load(file_path : &str) -> Result<Checkpoint, SimError> {
+        let reader = BufReader::new(File::open(file_path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+}
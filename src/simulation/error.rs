@@ -0,0 +1,76 @@
+use std::fmt;
+use polars::prelude::PolarsError;
+
+/// everything that can go wrong in the simulation module
+/// # Variants:
+///     InvalidPayoffTable - a payoff table with missing profiles or payoff vectors of the wrong length
+///     InvalidParameter - an unknown game name or parameters outside their allowed range
+///     GenomeMismatch - a genome that does not have k^memory_len genes, a history longer than the memory
+///         or an action outside the alphabet
+///     InvalidProbability - a NaN probability or selection weights that can not be sampled from
+///     ExtinctPopulation - no agents are left at the given round
+///     Io - reading or writing a file failed
+///     Serde - a json or toml document could not be read or written
+///     Polars - building or writing a dataframe failed
+#[derive(Debug)]
+pub enum SimError {
+    InvalidPayoffTable(String),
+    InvalidParameter(String),
+    GenomeMismatch(String),
+    InvalidProbability(String),
+    ExtinctPopulation { round_number : u32 },
+    Io(std::io::Error),
+    Serde(String),
+    Polars(PolarsError),
+}
+
+impl fmt::Display for SimError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SimError::InvalidPayoffTable(message) => write!(f, "Invalid payoff table: {}", message),
+            SimError::InvalidParameter(message) => write!(f, "Invalid parameter: {}", message),
+            SimError::GenomeMismatch(message) => write!(f, "Genome mismatch: {}", message),
+            SimError::InvalidProbability(message) => write!(f, "Invalid probability: {}", message),
+            SimError::ExtinctPopulation { round_number } => {
+                write!(f, "The population is extinct at round {}", round_number)
+            },
+            SimError::Io(e) => write!(f, "I/O error: {}", e),
+            SimError::Serde(message) => write!(f, "Serialization error: {}", message),
+            SimError::Polars(e) => write!(f, "Polars error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SimError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SimError::Io(e) => Some(e),
+            SimError::Polars(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for SimError {
+    fn from(e: std::io::Error) -> Self {
+        SimError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for SimError {
+    fn from(e: serde_json::Error) -> Self {
+        SimError::Serde(e.to_string())
+    }
+}
+
+impl From<toml::de::Error> for SimError {
+    fn from(e: toml::de::Error) -> Self {
+        SimError::Serde(e.to_string())
+    }
+}
+
+impl From<PolarsError> for SimError {
+    fn from(e: PolarsError) -> Self {
+        SimError::Polars(e)
+    }
+}
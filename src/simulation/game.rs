@@ -1,23 +1,56 @@
 use serde::{Deserialize, Serialize};
-use serde::ser::{SerializeMap, Serializer, SerializeStruct, SerializeSeq};
-use serde_json::{self, Map, Value};
-use serde::de::{self, Deserializer, MapAccess, Visitor};
+use serde::ser::{Serializer, SerializeStruct};
+use serde_json::{self, Value};
 use itertools::Itertools;
-use approx::assert_abs_diff_eq;
+use approx::abs_diff_eq;
 use rayon::prelude::*;
-use rand::distributions::{Distribution, WeightedIndex};
+use rand::distributions::Distribution;
+use rand::Rng;
+use rand_distr::Poisson;
 use std::{collections::HashMap, fs::File};
 use polars::prelude::*;
-use std::{hash::Hash};
-use ndarray::{Array, Array2, Axis};
 
 
 use crate::simulation::{
     agent::Agent,
-    types::{AgentRoundData, RoundState, GameBoard}
+    checkpoint::{Checkpoint, CheckpointRef},
+    genealogy::Genealogy,
+    genotype::{Genotype, MutationKind},
+    error::SimError,
+    iterated::{self, GameLength, Noise},
+    registry::{GenotypeHandle, GenotypeRegistry, Population, RoundScores},
+    rng::{self, StreamKind, CHUNK_SIZE},
+    selection::{Selection, SelectionDynamics},
+    sink::{AggregateColumns, ParquetSink, RoundColumns, RoundRows, RoundSink, SinkHandle},
+    types::{Action, AgentRoundData, DiversityMetrics, RoundState, GameBoard}
 };
 use super::types::AgentMetaData; // this is a bit faster than the standard HashMap
 
+/// how the population of the next round is computed from the scores of the current one
+/// # Variants:
+///     Stochastic - a finite population is resampled with the selection rule of the Game, see Selection
+///     MeanField - the population shares follow Lindgren's equation x_i' = d x_i (s_i - s_mean)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PopulationDynamics {
+    #[default]
+    Stochastic,
+    MeanField,
+}
+
+/// which groups of genotypes play each other in a round
+/// # Variants:
+///     WithReplacement - every multiset of genotypes plays, a genotype also meets copies of itself.
+///         The opponents of a player are n_players - 1 agents drawn from the population, a group scores with
+///         the multinomial probability of its opponents and the fitness is the score minus the share weighted mean
+///     Distinct - only groups of distinct genotypes play, a player scores its payoff times the share of every
+///         opponent and the mean score is divided by the number of agents, the scoring of older runs
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GroupSampling {
+    #[default]
+    WithReplacement,
+    Distinct,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Game {
     pub rounds : Vec<RoundState>,
@@ -28,6 +61,54 @@ pub struct Game {
     pub p_p: f64,
     pub p_d: f64,
     pub p_r: f64,
+    #[serde(default)]
+    pub seed: u64, // master seed, every random draw of the run is derived from it
+    #[serde(default)]
+    pub dynamics: PopulationDynamics,
+    #[serde(default = "default_extinction_threshold")]
+    pub extinction_threshold: f64, // mean field genotypes with a smaller share are removed
+    #[serde(default)]
+    pub game_length: GameLength, // how many moves each group plays
+    #[serde(default)]
+    pub execution_error: f64, // probability of playing another action than the genome says
+    #[serde(default)]
+    pub perception_error: f64, // probability of remembering another action than the opponent played
+    #[serde(default)]
+    pub checkpoint_every: u32, // rounds between two checkpoints, 0 turns checkpoints off
+    #[serde(default)]
+    pub checkpoint_path: Option<String>, // where run writes its checkpoints
+    #[serde(default = "default_keep_rounds")]
+    pub keep_rounds: bool, // when false rounds only holds the last round, use a sink to keep the others
+    #[serde(default)]
+    pub merge_equivalent: bool, // when true the exported rounds count the genotypes with the same Genotype::canonical together
+    #[serde(default)]
+    pub genealogy: Option<Genealogy>, // when set run records the parent of every new genotype
+    #[serde(default = "default_group_sampling")]
+    pub group_sampling: GroupSampling, // which groups play, games saved before it was added used Distinct
+    #[serde(default)]
+    pub selection: Selection, // how the Stochastic dynamics pick the next population, Proportional by default
+    #[serde(skip)]
+    pub sinks: Vec<SinkHandle>, // receive every round of the next run, see add_sink
+    #[serde(skip)]
+    pub registry: GenotypeRegistry, // the genotypes of the current run, the round loop only handles their indices
+}
+
+/// every group of a round as indices into the population with the mean payoff of each member
+type GroupScores = Vec<(Vec<usize>, Vec<f64>)>;
+
+/// a mutation of one agent: the index of the agent, the mutant genotype and the mutations applied to its genotype
+pub type MutationEvent = (usize, Genotype, Vec<MutationKind>);
+
+fn default_extinction_threshold() -> f64 {
+    1e-5
+}
+
+fn default_keep_rounds() -> bool {
+    true
+}
+
+fn default_group_sampling() -> GroupSampling {
+    GroupSampling::Distinct
 }
 
 
@@ -35,7 +116,7 @@ impl Serialize for Game {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where
             S: Serializer {
-        let mut state = serializer.serialize_struct("Game", 8)?;
+        let mut state = serializer.serialize_struct("Game", 21)?;
         state.serialize_field("rounds", &self.rounds)?;
         state.serialize_field("game_board", &self.game_board)?;
         state.serialize_field("is_test", &self.is_test)?;
@@ -44,6 +125,19 @@ impl Serialize for Game {
         state.serialize_field("p_p", &self.p_p)?;
         state.serialize_field("p_d", &self.p_d)?;
         state.serialize_field("p_r", &self.p_r)?;
+        state.serialize_field("seed", &self.seed)?;
+        state.serialize_field("dynamics", &self.dynamics)?;
+        state.serialize_field("extinction_threshold", &self.extinction_threshold)?;
+        state.serialize_field("game_length", &self.game_length)?;
+        state.serialize_field("execution_error", &self.execution_error)?;
+        state.serialize_field("perception_error", &self.perception_error)?;
+        state.serialize_field("checkpoint_every", &self.checkpoint_every)?;
+        state.serialize_field("checkpoint_path", &self.checkpoint_path)?;
+        state.serialize_field("keep_rounds", &self.keep_rounds)?;
+        state.serialize_field("merge_equivalent", &self.merge_equivalent)?;
+        state.serialize_field("genealogy", &self.genealogy)?;
+        state.serialize_field("group_sampling", &self.group_sampling)?;
+        state.serialize_field("selection", &self.selection)?;
         state.end()
     }
 
@@ -55,81 +149,124 @@ impl Game {
         game_board: GameBoard, 
         is_test : bool, 
         debug : bool,
-    ) -> Result<Game, String> {
+    ) -> Result<Game, SimError> {
         
         Ok(Game {
             rounds: Vec::new(),
-            game_board: game_board,
-            is_test : is_test,
-            debug : debug,
+            game_board,
+            is_test,
+            debug,
             d: 0.001,
             p_p: 2e-5,
             p_d: 1e-5,
             p_r: 1e-5,
+            seed: rand::random(),
+            dynamics: PopulationDynamics::Stochastic,
+            extinction_threshold: default_extinction_threshold(),
+            game_length: GameLength::default(),
+            execution_error: 0.0,
+            perception_error: 0.0,
+            checkpoint_every: 0,
+            checkpoint_path: None,
+            keep_rounds: true,
+            merge_equivalent: false,
+            genealogy: None,
+            group_sampling: GroupSampling::WithReplacement,
+            selection: Selection::default(),
+            sinks: Vec::new(),
+            registry: GenotypeRegistry::new(),
         })
     }
 
-    pub fn dump_to_json(&self, file_path : String){
+    /// draws the initial population from the master seed
+    pub fn random_agents(&self, n_agents : u32, memory_len : u32) -> Result<Vec<Agent>, SimError> {
+        let n_actions = self.game_board.n_actions;
+        let seed = self.seed;
+        (0..n_agents as usize).collect::<Vec<usize>>()
+            .par_chunks(CHUNK_SIZE)
+            .enumerate()
+            .flat_map_iter(|(chunk, indices)| {
+                let mut rng = rng::stream(seed, 0, StreamKind::Init, chunk as u64);
+                indices.iter()
+                    .map(|_| Agent::random_init_from_rng(memory_len, n_actions, &mut rng))
+                    .collect::<Vec<Result<Agent, SimError>>>()
+            })
+            .collect()
+    }
+
+    pub fn dump_to_json(&self, file_path : String) -> Result<(), SimError> {
         serde_json::to_writer(
-            std::fs::File::create(file_path).unwrap(), &self
-        ).unwrap();
+            std::fs::File::create(file_path)?, &self
+        )?;
+        Ok(())
     }
 
-    pub fn load_from_json(file_path : String) -> Game {
-        let contents = std::fs::read_to_string(file_path).expect("Failed to read file");
-        let json: Value = serde_json::from_str(&contents).unwrap();
-        serde_json::from_value(json).unwrap()
+    pub fn load_from_json(file_path : String) -> Result<Game, SimError> {
+        let contents = std::fs::read_to_string(file_path)?;
+        let json: Value = serde_json::from_str(&contents)?;
+        Ok(serde_json::from_value(json)?)
     }
 
-    pub fn dump_to_parquet(&self, df : &mut DataFrame, file_path : String)-> Result<(), Box<dyn std::error::Error>> {
+    pub fn dump_to_parquet(&self, df : &mut DataFrame, file_path : String)-> Result<(), SimError> {
         ParquetWriter::new(File::create(file_path)?).finish(df)?;
         Ok(())
     }
 
-    fn read_from_parquet(file_path : String) -> Result<DataFrame, Box<dyn std::error::Error>> {
+    pub fn read_from_parquet(file_path : String) -> Result<DataFrame, SimError> {
         let reader = ParquetReader::new(File::open(file_path)?);
         let df = reader.finish()?;
         Ok(df)
     }
 
 
-    pub fn round_state_to_dataframe(&self) -> Result<DataFrame, Box<dyn std::error::Error>> {
-        let mut df = DataFrame::default();
-
-        for round_state in self.rounds.clone() {
-            for (agent, data) in round_state.agent_data {
-                // Here you define how you want to flatten the structure
-                let row = Series::new("round_number", &[round_state.round_number]);
-                let agent_id = Series::new("agent_id", &[agent.id]);
-                let count = Series::new("count", &[data.count]);
-                let score = Series::new("score", &[data.score]);
-                let fitness = Series::new("fitness", &[data.fitness]);
-                let population_share = Series::new("population_share", &[data.population_share]);
+    /// one row per agent and round of the rounds kept in memory
+    pub fn round_state_to_dataframe(&self) -> Result<DataFrame, SimError> {
+        self.rounds_to_dataframe(RoundColumns::new())
+    }
 
-                // Create a temporary DataFrame for this iteration
-                let temp_df = DataFrame::new(vec![row, agent_id, count, score, fitness, population_share])?;
+    /// one row per round kept in memory with the number of genotypes and the population means
+    pub fn round_aggregates_to_dataframe(&self) -> Result<DataFrame, SimError> {
+        self.rounds_to_dataframe(AggregateColumns::new())
+    }
 
-                // Append the temporary DataFrame to the main DataFrame
-                df.vstack_mut(&temp_df)?;
-            }
+    fn rounds_to_dataframe<T: RoundRows>(&self, mut columns : T) -> Result<DataFrame, SimError> {
+        for round_state in self.rounds.iter() {
+            columns.push_round(round_state, self.seed);
         }
+        columns.to_dataframe()
+    }
 
-        Ok(df)
-    
+    /// every round played by the next run is handed to the sink, several sinks can be attached at once.
+    /// The run finishes the sink and removes it from the game, a later run needs sinks of its own
+    pub fn add_sink<S: RoundSink + 'static>(&mut self, sink : S) {
+        self.sinks.push(SinkHandle::new(sink));
+    }
+
+    /// streams the rounds of the next run to a parquet file with the columns of round_state_to_dataframe
+    /// # Args:
+    ///     file_path: &str - the parquet file, it is complete once run returns, with or without an error
+    ///     flush_every: u32 - the number of rounds buffered in memory before a row group is written
+    pub fn stream_to_parquet(&mut self, file_path : &str, flush_every : u32) -> Result<(), SimError> {
+        let sink = ParquetSink::new(file_path, flush_every)?;
+        self.add_sink(sink);
+        Ok(())
     }
 
-    pub fn agents_to_hashmap(agents : &Vec<Agent>)-> HashMap<Agent, AgentMetaData> {
-        let mut agents_map : HashMap<Agent, AgentMetaData> = HashMap::new();
+    /// counts the agents of every genotype, the histories of the agents are not part of the key
+    pub fn agents_to_hashmap(agents : &Vec<Agent>)-> HashMap<Genotype, AgentMetaData> {
+        let mut agents_map : HashMap<Genotype, AgentMetaData> = HashMap::new();
         
         for agent in agents {
-            if agents_map.contains_key(agent) {
-                let agent_metadata = agents_map.get_mut(agent).unwrap();
-                agent_metadata.count += 1;
-            } else {
-                let mut agent_metadata = AgentMetaData::new();
-                agent_metadata.count = 1;
-                agents_map.insert(agent.clone(), agent_metadata);
+            let genotype = &agent.genotype;
+            match agents_map.get_key_value(genotype) {
+                // the same genotype can reappear through another mutation, it keeps its oldest lineage
+                Some((key, _)) if key.birth_round > genotype.birth_round => {
+                    let metadata = agents_map.remove(genotype).unwrap_or_default();
+                    agents_map.insert(genotype.clone(), metadata);
+                },
+                _ => {},
             }
+            agents_map.entry(genotype.clone()).or_default().count += 1;
         }
         // we compute population share
         let agents_len = agents.len();
@@ -140,19 +277,217 @@ impl Game {
         agents_map
     }
 
-    pub fn run(&mut self, n_rounds : u32, agents : Vec<Agent>) {
-        let mut agents_map = Game::agents_to_hashmap(&agents);  
-        for i in 0..n_rounds {
-            let round_state = self.play_round(i, agents_map.clone(), agents.len() as u32);
-            self.rounds.push(round_state.clone());
-            agents_map = self.sample_new_agents(&round_state, agents.len() as u32); // Updated without redeclaration
-            if agents_map.keys().len() < self.game_board.n_players as usize { // we halt game if there are not enough agents
+    /// plays n_rounds rounds starting from the given population
+    /// # Returns:
+    ///     Result<(), SimError> - an error if the population dies out or the payoffs can not be computed,
+    ///     the rounds played before the error are kept in self.rounds
+    pub fn run(&mut self, n_rounds : u32, agents : Vec<Agent>) -> Result<(), SimError> {
+        let agents_map = Game::agents_to_hashmap(&agents);  
+        if let Some(genealogy) = self.genealogy.as_mut() {
+            // the initial agents may come from an earlier run, in this run they appear at its first round
+            for agent in agents.iter() {
+                genealogy.add_root(&agent.genotype, 0);
+            }
+        }
+        self.registry = GenotypeRegistry::new();
+        // the key of the population map keeps the oldest lineage of a genome, it is interned first
+        let mut sorted_keys : Vec<&Genotype> = agents_map.keys().collect();
+        sorted_keys.sort();
+        let mut histories : HashMap<&Genotype, Vec<Action>> = HashMap::new();
+        for agent in agents.iter() {
+            // the games of a genotype start from the history of its first agent
+            histories.entry(&agent.genotype).or_insert_with(|| agent.history());
+        }
+        for genotype in sorted_keys {
+            self.registry.intern_with_history(genotype, &histories[genotype]);
+        }
+        let population = Population::from_map(&mut self.registry, agents_map);
+        self.run_from(0, n_rounds, population, agents.len() as u32)
+    }
+
+    /// loads a checkpoint written by run and plays the remaining rounds,
+    /// the rounds are the same as the ones of an uninterrupted run with the same seed
+    /// # Args:
+    ///     file_path: &str - the checkpoint file
+    /// # Returns:
+    ///     Result<Game, SimError> - the game with all the rounds of the run
+    pub fn resume(file_path : &str) -> Result<Game, SimError> {
+        Game::resume_checkpoint(Checkpoint::load(file_path)?)
+    }
+
+    /// plays the remaining rounds of a checkpoint read with Checkpoint::load, the sinks are not part of a
+    /// checkpoint so the ones of the resumed run are attached to checkpoint.game before calling it
+    /// # Args:
+    ///     checkpoint: Checkpoint - the state of the interrupted run
+    /// # Returns:
+    ///     Result<Game, SimError> - the game with all the rounds of the run
+    pub fn resume_checkpoint(checkpoint : Checkpoint) -> Result<Game, SimError> {
+        let mut game = checkpoint.game;
+        // the genotypes seen before the checkpoint keep their lineage and history if they arise again
+        for agent in checkpoint.genotypes.iter() {
+            game.registry.intern_agent(agent);
+        }
+        let entries = checkpoint.population.into_iter()
+            .map(|(agent, metadata)| (game.registry.intern_agent(&agent), metadata))
+            .collect();
+        let population = Population::from_entries(&game.registry, entries);
+        game.run_from(checkpoint.next_round, checkpoint.n_rounds, population, checkpoint.n_agents)?;
+        Ok(game)
+    }
+
+    /// plays the rounds start..n_rounds and closes and removes the sinks, also when a round fails so their files stay readable
+    /// # Returns:
+    ///     Result<(), SimError> - the error of the rounds if there is one, otherwise the first error of the sinks
+    fn run_from(
+        &mut self, 
+        start : u32, 
+        n_rounds : u32, 
+        population : Population, 
+        n_agents : u32
+    ) -> Result<(), SimError> {
+        let played = self.play_rounds(start, n_rounds, population, n_agents);
+        let mut finished = Ok(());
+        // a finished sink can not take more rounds, the next run starts without sinks
+        for sink in std::mem::take(&mut self.sinks) {
+            let result = sink.finish();
+            if finished.is_ok() {
+                finished = result;
+            }
+        }
+        played.and(finished)
+    }
+
+    /// plays the rounds start..n_rounds, writing a checkpoint every checkpoint_every rounds
+    fn play_rounds(
+        &mut self, 
+        start : u32, 
+        n_rounds : u32, 
+        mut population : Population, 
+        n_agents : u32
+    ) -> Result<(), SimError> {
+        for i in start..n_rounds {
+            let scores = self.play_round(i, &population, n_agents)?;
+            // the genotypes are only cloned out of the registry for the exports
+            let round_state = self.round_state(i, &population, &scores);
+            for sink in self.sinks.iter() {
+                sink.write_round(&round_state, self.seed)?;
+            }
+            if !self.keep_rounds {
+                self.rounds.clear();
+            }
+            self.rounds.push(round_state);
+            population = match self.dynamics {
+                PopulationDynamics::Stochastic => self.sample_new_agents(&scores, i, n_agents)?,
+                PopulationDynamics::MeanField => self.mean_field_step(&scores, i, n_agents)?,
+            };
+            self.prune_registry(&population);
+            if population.len() < self.min_genotypes() { // we halt game if there are not enough agents
                 println!("game halted at timestep: {:?} as there is only {:?} 
                 agents left and {:?} are needed to play the game", 
-                i, agents_map.keys().len(), self.game_board.n_players);
+                i, population.len(), self.min_genotypes());
                 break;
             }
+            if let Some(path) = &self.checkpoint_path {
+                if self.checkpoint_every > 0 && (i + 1) % self.checkpoint_every == 0 {
+                    self.checkpoint(path, &population, i + 1, n_rounds, n_agents)?;
+                }
+            }
+
+        }
+        Ok(())
+    }
+
+    /// drops the genotypes that are not in the population from the registry. With a genealogy the genotypes of
+    /// the tree are kept, a genotype that arises again then keeps the lineage the genealogy gives it, without one
+    /// it is registered again with the lineage of its new parent
+    fn prune_registry(&mut self, population : &Population) {
+        let mut alive = vec![false; self.registry.capacity()];
+        for &handle in population.handles.iter() {
+            alive[handle as usize] = true;
+        }
+        let genealogy = self.genealogy.as_ref();
+        self.registry.prune(&alive, |genotype| genealogy.is_some_and(|genealogy| genealogy.contains(genotype)));
+    }
+
+    /// writes the state of the run before round next_round to file_path
+    fn checkpoint(
+        &self, 
+        file_path : &str, 
+        population : &Population, 
+        next_round : u32, 
+        n_rounds : u32, 
+        n_agents : u32
+    ) -> Result<(), SimError> {
+        let checkpoint = CheckpointRef {
+            game: self,
+            population: population.handles.iter().zip(population.metadata.iter())
+                .map(|(&handle, metadata)| (self.registry.agent(handle), metadata.clone()))
+                .collect(),
+            genotypes: self.registry.registered().into_iter().map(|handle| self.registry.agent(handle)).collect(),
+            next_round,
+            n_rounds,
+            n_agents,
+        };
+        checkpoint.save(file_path)
+    }
+
+    /// the exported state of a round, the population map keyed by the genotypes of the registry.
+    /// With merge_equivalent the genotypes are keyed by their canonical form and the equivalent ones are
+    /// merged, see AgentRoundData::merge, the population that plays keeps the genomes as they are
+    fn round_state(&self, round_number : u32, population : &Population, scores : &RoundScores) -> RoundState {
+        if !self.merge_equivalent {
+            return RoundState {
+                round_number,
+                n_players: self.game_board.n_players,
+                agent_data: scores.handles.iter().zip(scores.data.iter())
+                    .map(|(&handle, data)| (self.registry.get(handle).clone(), data.clone()))
+                    .collect(),
+                metrics: DiversityMetrics::from_shares(population.metadata.iter().map(|metadata| metadata.population_share)),
+            };
+        }
+
+        let mut agent_data : HashMap<Genotype, AgentRoundData> = HashMap::new();
+        for (&handle, data) in scores.handles.iter().zip(scores.data.iter()) {
+            let canonical = self.registry.get(handle).canonical();
+            let (key, merged) = match agent_data.remove_entry(&canonical) {
+                // the merged genotype keeps the oldest lineage, like agents_to_hashmap
+                Some((key, mut merged)) => {
+                    merged.merge(data);
+                    (if canonical.birth_round < key.birth_round { canonical } else { key }, merged)
+                },
+                None => (canonical, data.clone()),
+            };
+            agent_data.insert(key, merged);
+        }
+        let mut shares : HashMap<Genotype, f64> = HashMap::new();
+        for (&handle, metadata) in population.handles.iter().zip(population.metadata.iter()) {
+            *shares.entry(self.registry.get(handle).canonical()).or_insert(0.0) += metadata.population_share;
+        }
+        let metrics = DiversityMetrics::from_shares(
+            shares.into_iter().sorted_by(|a, b| a.0.cmp(&b.0)).map(|(_, share)| share)
+        );
+        RoundState { round_number, n_players: self.game_board.n_players, agent_data, metrics }
+    }
+
+    /// the diversity of every round kept in memory as (round_number, metrics), 
+    /// attach an aggregates sink to keep them when keep_rounds is false
+    pub fn metrics(&self) -> Vec<(u32, DiversityMetrics)> {
+        self.rounds.iter().map(|round_state| (round_state.round_number, round_state.metrics)).collect()
+    }
 
+    /// the number of genotypes needed to form a group
+    pub fn min_genotypes(&self) -> usize {
+        match self.group_sampling {
+            GroupSampling::WithReplacement => 1,
+            GroupSampling::Distinct => self.game_board.n_players as usize,
+        }
+    }
+
+    /// the trembling hand error rates used when the groups play
+    pub fn noise(&self) -> Noise {
+        Noise {
+            execution: self.execution_error,
+            perception: self.perception_error,
         }
     }
 
@@ -163,113 +498,251 @@ impl Game {
         
     }
 
-    pub fn compute_next_probability(&self, roundData : &RoundState) -> HashMap<Agent, f64> {
-        let mut outcome_probabilities : HashMap<Agent, f64> = HashMap::new();
-        let mut total_probability = 0.0;
+    pub fn compute_next_probability(&self, round_data : &RoundState) -> Result<HashMap<Genotype, f64>, SimError> {
+        // iterate in genotype order so the sums do not depend on the HashMap ordering
+        let (genotypes, data) : (Vec<&Genotype>, Vec<&AgentRoundData>) = round_data.agent_data.iter()
+            .sorted_by(|a, b| a.0.cmp(b.0))
+            .unzip();
+        let data : Vec<AgentRoundData> = data.into_iter().cloned().collect();
+        let probabilities = self.next_probabilities(&genotypes, &data)?;
+        Ok(genotypes.into_iter().cloned().zip(probabilities).collect())
+    }
+
+    /// the probabilities of compute_next_probability for genotypes sorted by genotype
+    /// # Args:
+    ///     genotypes: &[&Genotype] - the genotypes of the round, only used in the error messages
+    ///     data: &[AgentRoundData] - the scores and shares of the genotypes, indexed like genotypes
+    /// # Returns:
+    ///     Result<Vec<f64>, SimError> - the probability of each genotype to be sampled, indexed like genotypes
+    pub(crate) fn next_probabilities(&self, genotypes : &[&Genotype], data : &[AgentRoundData]) -> Result<Vec<f64>, SimError> {
+        let mut outcome_probabilities : Vec<f64> = Vec::with_capacity(data.len());
         
-        for (i, (agent, agent_data)) 
-            in roundData.agent_data.iter().enumerate() {
+        for (i, agent_data) in data.iter().enumerate() {
             
             let first_factor = self.d * self.make_positive(agent_data.fitness)*agent_data.population_share;
             
-            let second_factor : f64;
-            if agent_data.score != 0.0 {
-                second_factor = 1.0 - roundData.agent_data
-                    .iter().enumerate().
-                    map(|(j, (agent, inner_agent_data))|
+            let second_factor = if agent_data.score != 0.0 {
+                1.0 - data
+                    .iter().
+                    map(|inner_agent_data|
                         if i != 0 {
                             (inner_agent_data.score * inner_agent_data.population_share)/ agent_data.score
                         } else {
                             0.0
                         }
-                    ).sum::<f64>();   
+                    ).sum::<f64>()
             } else {
-                second_factor = 1.0;
-            }                 
+                1.0
+            };
                 
             let second_factor = self.make_positive(second_factor);
             //check is not NaN
             if first_factor.is_nan() {
-                panic!("Nan value detected in probability computation for first factor");
+                return Err(SimError::InvalidProbability(
+                    format!("Nan value detected in probability computation for first factor of agent {}", genotypes[i].id)
+                ));
             }
             if second_factor.is_nan() {
-                panic!("Nan value detected in probability computation for second factor");
+                return Err(SimError::InvalidProbability(
+                    format!("Nan value detected in probability computation for second factor of agent {}", genotypes[i].id)
+                ));
             }
-            let probability = first_factor * second_factor;
-            total_probability += probability;
-            
-            outcome_probabilities.insert(agent.clone(), probability);
+            outcome_probabilities.push(first_factor * second_factor);
         }
 
         
         // Test if the sum of the probabilities is 1
-        let sum : f64 = outcome_probabilities.iter().map(|(_, &prob)| prob).sum();
+        let sum : f64 = outcome_probabilities.iter().sum();
         
         if sum != 0.0 {
-            for probability in outcome_probabilities.values_mut() {
+            for probability in outcome_probabilities.iter_mut() {
                 *probability /= sum;
             }
         }
 
-        let corrected_sum: f64 = outcome_probabilities.values().sum();
+        let corrected_sum: f64 = outcome_probabilities.iter().sum();
         if corrected_sum != 1.0 {
             let correction_factor = 1.0 / corrected_sum;
-            for probability in outcome_probabilities.values_mut() {
+            for probability in outcome_probabilities.iter_mut() {
                 *probability *= correction_factor;
             }
         }
 
-        let sum : f64 = outcome_probabilities.iter().map(|(_, &prob)| prob).sum();
+        let sum : f64 = outcome_probabilities.iter().sum();
         
-        assert_abs_diff_eq!(sum, 1.0, epsilon = 0.0001);
-        for (_, prob) in outcome_probabilities.iter() {
-            assert!(*prob >= 0.0); // proba should be positive
-            assert!(*prob <= 1.0); // proba should be less than 1
+        if !abs_diff_eq!(sum, 1.0, epsilon = 0.0001) {
+            return Err(SimError::InvalidProbability(format!("The probabilities sum to {} instead of 1", sum)));
+        }
+        for (genotype, prob) in genotypes.iter().zip(outcome_probabilities.iter()) {
+            // proba should be between 0 and 1
+            if !(0.0..=1.0).contains(prob) {
+                return Err(SimError::InvalidProbability(
+                    format!("Probability {} of agent {} is outside [0, 1]", prob, genotype.id)
+                ));
+            }
         }
         
-        outcome_probabilities
+        Ok(outcome_probabilities)
     }
 
-    /// mutates the agents in place
-    /// TODO: this could be optimized further by using a parallel iterator
-    /// and by using a dictionary to store the agents
-    pub fn apply_mutations(&self, agents : &mut Vec<Agent>) {
-
-        agents.par_iter_mut().for_each(|agent| {  
-            agent.mutate(self.p_p, self.p_d, self.p_r);
-            // Mutation logic is applied directly to each agent in the vector,
-            // so there's no need to return a new vector.
-            // The mutate method should modify the agent in place.
-        });
+    /// mutates the genotypes of the sampled agents, the registry is left as it is
+    /// each fixed size chunk of agents draws from its own stream of the round
+    /// # Args:
+    ///     handles: &[GenotypeHandle] - the genotype of every agent
+    ///     round_number: u32 - the round, also the birth round of the mutants
+    /// # Returns:
+    ///     Vec<MutationEvent> - the index, the mutant and the applied mutations of every agent whose genome changed,
+    ///     in the order of the agents
+    pub fn apply_mutations(&self, handles : &[GenotypeHandle], round_number : u32) -> Vec<MutationEvent> {
+
+        handles.par_chunks(CHUNK_SIZE).enumerate().flat_map_iter(|(chunk, handles)| {  
+            let mut rng = rng::stream(self.seed, round_number, StreamKind::Mutation, chunk as u64);
+            let mut events = Vec::new();
+            for (offset, &handle) in handles.iter().enumerate() {
+                let parent = self.registry.get(handle);
+                let Some((mut mutant, kinds)) = parent.mutant(self.p_p, self.p_d, self.p_r, &mut rng) else {
+                    continue;
+                };
+                if mutant != *parent {
+                    mutant.set_parent(parent, round_number);
+                    events.push((chunk * CHUNK_SIZE + offset, mutant, kinds));
+                }
+            }
+            // only the mutants are cloned, the caller interns them
+            events
+        }).collect()
     }
 
-    /// this function first samples new agents based on the fitness from last round data
+    /// this function first samples new agents with the selection rule from last round data, then mutates them
+    /// # Args:
+    ///     scores: &RoundScores - the scores and shares of the round that was just played
+    ///     round_number: u32 - the round that was just played
+    ///     n_agents: u32 - the number of agents to sample
     pub fn sample_new_agents(
-        &mut self, round_data : &RoundState, n_agents : u32
-    ) -> HashMap<Agent, AgentMetaData>{
-        if round_data.agent_data.len() == 0 {
-            panic!("No agents in round data map is empty");
-        }
-        let probability_distribution = self.compute_next_probability(round_data);
-        // Convert the probability distribution into a format suitable for sampling
-        let agents: Vec<Agent> = probability_distribution.keys().cloned().collect();
-        let probabilities: Vec<f64> = probability_distribution.values().cloned().collect();
-        if self.debug {
-            println!("Probabilities: {:?}", probabilities);
-        }
-        let dist = WeightedIndex::new(&probabilities).unwrap();
-    
-        // Sample in parallel
-        let mut new_agents: Vec<Agent> = (0..n_agents).into_par_iter()
-            .map(|_| {
-                let mut rng = rand::thread_rng();
-                agents[dist.sample(&mut rng)].clone()
+        &mut self, scores : &RoundScores, round_number : u32, n_agents : u32
+    ) -> Result<Population, SimError> {
+        if scores.is_empty() {
+            return Err(SimError::ExtinctPopulation { round_number });
+        }
+        let mut new_handles = self.selection.select(self, scores, round_number, n_agents)?;
+
+        let events = self.apply_mutations(&new_handles, round_number);
+        for (index, mutant, kinds) in events {
+            if let Some(genealogy) = self.genealogy.as_mut() {
+                genealogy.record(self.registry.get(new_handles[index]), &mutant, &kinds, round_number);
+            }
+            // a new genotype starts its games from the history of its parent
+            let parent_history = self.registry.history(new_handles[index]).to_vec();
+            new_handles[index] = self.registry.intern_with_history(&mutant, &parent_history);
+        }
+
+        let mut counts : HashMap<GenotypeHandle, u32> = HashMap::new();
+        for &handle in new_handles.iter() {
+            *counts.entry(handle).or_insert(0) += 1;
+        }
+        let entries = counts.into_iter()
+            .map(|(handle, count)| (handle, AgentMetaData {
+                count,
+                population_share: count as f64 / n_agents as f64,
+            }))
+            .collect();
+        Ok(Population::from_entries(&self.registry, entries))
+    }
+
+    /// deterministic replicator step from Lindgren's model
+    /// x_i' = x_i + d * x_i * (s_i - s_mean) where s_mean is the share weighted mean score.
+    /// Mutants are injected with a share of 1 / n_agents taken from their parent, the number of mutants of
+    /// a genotype is drawn from a Poisson distribution with mean n_agents * x_i * (p_p + p_d + p_r).
+    /// Genotypes below extinction_threshold are removed and the shares renormalized.
+    /// # Args:
+    ///     scores: &RoundScores - the scores and shares of the round that was just played
+    ///     round_number: u32 - the round that was just played
+    ///     n_agents: u32 - the population size used for the mutation rate, the mutant share and the counts
+    pub fn mean_field_step(
+        &mut self, scores : &RoundScores, round_number : u32, n_agents : u32
+    ) -> Result<Population, SimError> {
+        if scores.is_empty() {
+            return Err(SimError::ExtinctPopulation { round_number });
+        }
+
+        let mean_score : f64 = scores.data.iter()
+            .map(|data| data.population_share * data.score)
+            .sum();
+
+        let mut shares : Vec<(GenotypeHandle, f64)> = scores.handles.iter().zip(scores.data.iter())
+            .map(|(&handle, data)| {
+                let share = data.population_share + self.d * data.population_share * (data.score - mean_score);
+                (handle, share.max(0.0))
             })
             .collect();
 
-        self.apply_mutations(&mut new_agents); // we modify the agents in place 
-        let output = Self::agents_to_hashmap(&new_agents);
-        return output;
+        // mutations, drawn serially from a single stream of the round
+        let mutant_share = 1.0 / n_agents as f64;
+        let mutation_rate = self.p_p + self.p_d + self.p_r;
+        let mut rng = rng::stream(self.seed, round_number, StreamKind::Mutation, 0);
+        let mut mutants : Vec<(GenotypeHandle, f64)> = Vec::new();
+        if mutation_rate > 0.0 {
+            for (handle, share) in shares.iter_mut() {
+                let expected = n_agents as f64 * *share * mutation_rate;
+                if expected <= 0.0 {
+                    continue;
+                }
+                let n_mutants = Poisson::new(expected)
+                    .map_err(|e| SimError::InvalidProbability(format!("Invalid mutation rate {}: {}", expected, e)))?
+                    .sample(&mut rng) as u64;
+                for _ in 0..n_mutants {
+                    if *share < mutant_share {
+                        break;
+                    }
+                    let draw = rng.gen::<f64>() * mutation_rate;
+                    let kind = if draw < self.p_p {
+                        MutationKind::Point
+                    } else if draw < self.p_p + self.p_d {
+                        MutationKind::Duplication
+                    } else {
+                        MutationKind::Split
+                    };
+                    let genotype = self.registry.get(*handle);
+                    let mut mutant = genotype.clone();
+                    if !mutant.apply_mutation(kind, &mut rng) {
+                        continue;
+                    }
+                    mutant.set_parent(genotype, round_number);
+                    if let Some(genealogy) = self.genealogy.as_mut() {
+                        genealogy.record(genotype, &mutant, &[kind], round_number);
+                    }
+                    *share -= mutant_share;
+                    let parent_history = self.registry.history(*handle).to_vec();
+                    mutants.push((self.registry.intern_with_history(&mutant, &parent_history), mutant_share));
+                }
+            }
+        }
+
+        let mut population : HashMap<GenotypeHandle, f64> = HashMap::new();
+        for (handle, share) in shares.into_iter().chain(mutants) {
+            *population.entry(handle).or_insert(0.0) += share;
+        }
+        let mut population : Vec<(GenotypeHandle, f64)> = population.into_iter()
+            .sorted_by(|a, b| self.registry.get(a.0).cmp(self.registry.get(b.0)))
+            .collect();
+
+        // normalise, remove the extinct genotypes and normalise again
+        let total : f64 = population.iter().map(|(_, share)| share).sum();
+        population.retain(|(_, share)| *share / total >= self.extinction_threshold);
+        let total : f64 = population.iter().map(|(_, share)| share).sum();
+        if total.is_nan() || total <= 0.0 {
+            return Err(SimError::ExtinctPopulation { round_number });
+        }
+
+        let entries = population.into_iter().map(|(handle, share)| {
+            let population_share = share / total;
+            let metadata = AgentMetaData {
+                count: (population_share * n_agents as f64).round() as u32,
+                population_share,
+            };
+            (handle, metadata)
+        }).collect();
+        Ok(Population::from_entries(&self.registry, entries))
     }
 
     /// this is the core function of the game, 
@@ -278,103 +751,175 @@ impl Game {
     fn play_round(
         &mut self, 
         round_number : u32, 
-        agents : HashMap<Agent, AgentMetaData>,
+        population : &Population,
         total_players : u32
-    ) -> RoundState {
+    ) -> Result<RoundScores, SimError> {
 
-        if agents.keys().len() == 0 {
-            panic!("No agents in agents map is empty this should not happen within play_round");
+        if population.is_empty() {
+            return Err(SimError::ExtinctPopulation { round_number });
+        }
+        if self.debug {
+            println!("Agents in play_round: {:?}", population.handles.iter()
+                .map(|&handle| &self.registry.get(handle).id).collect::<Vec<_>>());
         }
-        println!("Agents in play_round: {:?}", agents.keys());
-
-        let combinations = self.enumerate_combinations(
-            &agents.keys().cloned().collect()   
-        );
-
 
-        let mut inter_mediate_compute : HashMap<Agent,f64> = HashMap::new();
-        // this loop computes the score for all the agents
-       
-        for (agent_combinations, actions, scores) in combinations { 
-            // we compute the scores for each agent and adds them to a temporary dictionary/hashmap
-            for (i, agent) in agent_combinations.iter().enumerate() {
-                // this computes the score for agent i by weighting the score of the ith agent by 
-                // forall j in 0 ...n  where j != n do scores[i] * agents[j].population_share 
-                let mut agent_combinations_clone = agent_combinations.clone();
-                agent_combinations_clone.remove(i); // we remove the agent itself
-                let mut score = 0.0;
-                for agent in agent_combinations_clone.iter() {
-                    // we weight the score by the population share of the opponents
-                    score += scores[i] * agents.get(agent).unwrap().population_share;
-                }
+        let combinations = self.enumerate_combinations(population, round_number)?;
+
+        let shares : Vec<f64> = population.metadata.iter().map(|metadata| metadata.population_share).collect();
+        // map: the groups are scored in parallel, each group gives the score of its genotypes
+        let group_scores : Vec<Vec<(usize, f64)>> = combinations.par_iter()
+            .map(|(agent_combinations, scores)| match self.group_sampling {
+                GroupSampling::WithReplacement => multiset_scores(agent_combinations, scores, &shares),
+                GroupSampling::Distinct => (0..agent_combinations.len()).map(|i| {
+                    let mut score = 0.0;
+                    for (_, &opponent) in agent_combinations.iter().enumerate().filter(|&(j, _)| j != i) {
+                        // we weight the score by the population share of the opponents
+                        score += scores[i] * shares[opponent];
+                    }
+                    (agent_combinations[i], score)
+                }).collect(),
+            })
+            .collect();
 
-                if inter_mediate_compute.contains_key(agent) {
-                    let old_score = inter_mediate_compute.get_mut(agent).unwrap();
-                    *old_score += score;
-                } else {
-                    let cloned_agent = agent.clone();
-                    inter_mediate_compute.insert(cloned_agent, score);
-                }    
-            }    
+        // reduce: the group scores are added serially in the order of the combinations,
+        // so the sums are the same whatever the number of threads
+        let mut inter_mediate_compute : Vec<f64> = vec![0.0; population.len()];
+        let mut played : Vec<bool> = vec![false; population.len()];
+        for (agent, score) in group_scores.into_iter().flatten() {
+            inter_mediate_compute[agent] += score;
+            played[agent] = true;
+        }
+        if self.debug {
             println!("intermediate compute: {:?}", inter_mediate_compute);
         }
 
-        if inter_mediate_compute.len() == 0 {
-            panic!("No agents in inter_mediate_compute map is empty this should not happen within play_round");
+        if !played.iter().any(|&played| played) {
+            return Err(SimError::ExtinctPopulation { round_number });
         }
 
-        let average_score = inter_mediate_compute.iter().
-            map(
-                |(agent, score)| 
-                score * agents.get(agent).unwrap().population_share as f64 // mutliply agent score by its share of the population
-            ).sum::<f64>() / total_players as f64;
-
-        let agent_data : HashMap<Agent, AgentRoundData> = inter_mediate_compute.iter().map(
-            |(agent, score)| {
-            let agent_metadata = agents.get(agent).unwrap();
+        let average_score = inter_mediate_compute.iter().zip(shares.iter()).zip(played.iter())
+            .filter(|(_, &played)| played)
+            .map(
+                |((score, share), _)| 
+                score * share // mutliply agent score by its share of the population
+            ).sum::<f64>();
+        let average_score = match self.group_sampling {
+            GroupSampling::WithReplacement => average_score,
+            GroupSampling::Distinct => average_score / total_players as f64,
+        };
+
+        let mut round_scores = RoundScores::default();
+        for (index, score) in inter_mediate_compute.into_iter().enumerate().filter(|&(index, _)| played[index]) {
+            let agent_metadata = &population.metadata[index];
             let (count, population_share) = (agent_metadata.count, agent_metadata.population_share);
             let fitness = score -average_score ;
-            let cloned_agent = agent.clone();
-            (cloned_agent, AgentRoundData::from(count, *score, fitness, population_share))
-        }).collect();
-
-        if agent_data.len() == 0 {
-            panic!("No agents in agent data map is empty this should not happen within play_round");
-        }
-        //TODO fix that agent_data might be an empty HashMap
-        return RoundState {
-            round_number: round_number,
-            agent_data: agent_data,
+            round_scores.handles.push(population.handles[index]);
+            round_scores.data.push(AgentRoundData::from(count, score, fitness, population_share));
         }
+        Ok(round_scores)
 
     }
 
-    /// this enumerates all possible combinations of agents and their actions, score and against each other
+    /// this enumerates all possible combinations of genotypes and the score of the iterated game
+    /// they play against each other, the length of the game is set by game_length and the errors
+    /// by execution_error and perception_error.
+    /// In asymmetric games every group is played in all its seatings and the payoffs are averaged, see play_all_seatings.
+    /// Every genotype plays through an Agent with the history of the registry, see GenotypeRegistry::agent,
+    /// so every game starts from the history the genotype entered the population with.
+    /// Noiseless games do not depend on the round, their payoffs are cached in the registry
     /// # Args:
-    ///     agents: Vec<Agent> - the agents to be used in the game
+    ///     population: &Population - the genotypes to be used in the game, sorted by genotype
+    ///     round_number: u32 - the round, each combination draws its errors from its own stream of the round
     ///# Returns:
-    ///     Vec<(Vec<Agent>, Vec<bool>, Vec<f64>)> - a vector of tuples 
-    ///     Vec<Agent> - the agents in the combination
-    ///     Vec<bool> - the actions of the agents in the combination where Agent[i] takes action[i]
-    ///     Vec<f64> - the score of the agents in the combination where Agent[i] gets score[i]
+    ///     Result<Vec<(Vec<usize>, Vec<f64>)>, SimError> - a vector of tuples 
+    ///     Vec<usize> - the indices in population of the genotypes in the combination
+    ///     Vec<f64> - the mean payoff per move of the genotypes in the combination where index[i] gets score[i]
     fn enumerate_combinations(
-        &self, agents: &Vec<Agent>
-    ) -> Vec<(Vec<Agent>, Vec<bool>, Vec<f64>)> 
+        &mut self, population : &Population, round_number : u32
+    ) -> Result<GroupScores, SimError>
     {   
-        let mut results = Vec::new();
-        // Generate all unique combinations of agents of size total
+        // Generate all unique combinations of genotypes of size total
         let n_players = self.game_board.n_players as usize;
+        let noise = self.noise();
+        // the members of a group are seated in population order, asymmetric games are played in every seating
+        let symmetric = self.game_board.is_symmetric();
+        let agents : Vec<Agent> = population.handles.iter()
+            .map(|&handle| self.registry.agent(handle))
+            .collect();
+        let combinations : Vec<Vec<usize>> = match self.group_sampling {
+            GroupSampling::WithReplacement => (0..population.len()).combinations_with_replacement(n_players).collect(),
+            GroupSampling::Distinct => (0..population.len()).combinations(n_players).collect(),
+        };
+
+        // the groups are played in parallel, group i draws from stream i of the round whatever thread plays it
+        // and the results are collected in the order of the combinations
+        let played : Vec<(Vec<usize>, Vec<f64>, bool)> = combinations.into_par_iter().enumerate()
+            .map(|(i, combination)| {
+                let group : Vec<GenotypeHandle> = combination.iter().map(|&index| population.handles[index]).collect();
+                if let Some(score) = self.registry.group_payoff(&group) {
+                    return Ok((combination, score.clone(), false));
+                }
+                let mut rng = rng::stream(self.seed, round_number, StreamKind::Play, i as u64);
+                let players : Vec<&Agent> = combination.iter().map(|&index| &agents[index]).collect();
+                let score = if symmetric {
+                    iterated::play(&self.game_board, &players, self.game_length, noise, &mut rng)?
+                } else {
+                    iterated::play_all_seatings(&self.game_board, &players, self.game_length, noise, &mut rng)?
+                };
+                Ok((combination, score, true))
+            })
+            .collect::<Result<_, SimError>>()?;
 
-        for combination in agents.iter().sorted().combinations(n_players) {
-            let agents_combination: Vec<Agent> = combination.iter().
-                map(|agent| *agent).cloned().collect();
-            let actions: Vec<bool> = agents_combination.iter().
-                map(|agent| agent.get_action()).collect();
-            let score = self.game_board.get_payoff(&actions);
-            results.push((agents_combination, actions, score));
+        let mut results = Vec::with_capacity(played.len());
+        for (combination, score, is_new) in played {
+            if is_new && noise.is_noiseless() {
+                let group = combination.iter().map(|&index| population.handles[index]).collect();
+                self.registry.cache_group_payoff(group, score.clone());
+            }
+            results.push((combination, score));
         }
-        results
+        Ok(results)
     }
 
 
-}
\ No newline at end of file
+}
+
+/// the expected payoff a group adds to each of its genotypes when the n_players - 1 opponents of a player
+/// are drawn from the population, the copies of a genotype share the mean of their payoffs
+/// # Args:
+///     group: &[usize] - the population indices of the members in increasing order, a multiset
+///     scores: &[f64] - the payoff of every member
+///     shares: &[f64] - the population share of every population index
+/// # Returns:
+///     Vec<(usize, f64)> - the population index and the weighted payoff of every distinct genotype of the group
+fn multiset_scores(group : &[usize], scores : &[f64], shares : &[f64]) -> Vec<(usize, f64)> {
+    let mut results = Vec::new();
+    let mut start = 0;
+    while start < group.len() {
+        let genotype = group[start];
+        let copies = group[start..].iter().take_while(|&&index| index == genotype).count();
+        let payoff = scores[start..start + copies].iter().sum::<f64>() / copies as f64;
+        // the opponents are the group without one copy of the genotype
+        let opponents = group[..start].iter().chain(group[start + 1..].iter());
+        results.push((genotype, payoff * multinomial_probability(opponents, shares)));
+        start += copies;
+    }
+    results
+}
+
+/// the probability of drawing the multiset of indices with replacement, (n! / prod c_j!) * prod share_j^c_j
+/// # Args:
+///     indices: I - the multiset in increasing order so the copies of an index are next to each other
+///     shares: &[f64] - the probability of every index
+fn multinomial_probability<'a, I: Iterator<Item = &'a usize>>(indices : I, shares : &[f64]) -> f64 {
+    let mut probability = 1.0;
+    let mut previous = None;
+    let mut copies = 0;
+    for (drawn, &index) in indices.enumerate() {
+        copies = if previous == Some(index) { copies + 1 } else { 1 };
+        previous = Some(index);
+        // builds n! / prod c_j! one draw at a time
+        probability *= shares[index] * (drawn + 1) as f64 / copies as f64;
+    }
+    probability
+}
@@ -0,0 +1,269 @@
+//! the classic 2 player social dilemmas, the N player group games and a few games with more than 2 actions
+//! action 1 is cooperate (dove, stag, swerve) and 0 is defect (hawk, hare, straight),
+//! for battle of the sexes and matching pennies 1 and 0 are simply the two options.
+//! These two are asymmetric, Game plays their groups in both seatings and averages the payoffs.
+//! The symmetric games are written in the usual T, R, P, S notation:
+//!     R - reward for mutual cooperation
+//!     S - sucker's payoff for cooperating against a defector
+//!     T - temptation to defect against a cooperator
+//!     P - punishment for mutual defection
+//! The N player games only depend on the number of cooperators in the group
+//! so their payoff tables are generated for any n_players.
+
+use std::collections::HashMap;
+use crate::simulation::{error::SimError, types::{Action, GameBoard, COOPERATE, DEFECT}};
+
+const C : Action = COOPERATE;
+const D : Action = DEFECT;
+
+/// the largest group of the N player games, their payoff tables have 2^n_players profiles of n_players payoffs
+pub const MAX_GROUP_SIZE : u32 = 16;
+
+/// the parameters used by GameBoard::new for each named game
+pub fn default_params(game_name : &str, n_players : u32) -> Option<Vec<f64>> {
+    match game_name {
+        "prisoners_dilemma" => Some(vec![5.0, 3.0, 1.0, 0.0]), // T, R, P, S
+        "hawk_dove" => Some(vec![2.0, 4.0]), // V, C
+        "snowdrift" => Some(vec![4.0, 2.0]), // b, c
+        "stag_hunt" => Some(vec![3.0, 4.0, 2.0, 0.0]), // T, R, P, S
+        "chicken" => Some(vec![4.0, 3.0, 0.0, 1.0]), // T, R, P, S
+        "harmony" => Some(vec![3.0, 4.0, 1.0, 2.0]), // T, R, P, S
+        "battle_of_the_sexes" => Some(vec![2.0, 1.0]), // preferred, other
+        "matching_pennies" => Some(vec![1.0]), // stake
+        "rock_paper_scissors" => Some(vec![1.0, -1.0, 0.0]), // win, lose, tie
+        "optional_prisoners_dilemma" => Some(vec![5.0, 3.0, 1.0, 0.0, 2.0]), // T, R, P, S, loner
+        "public_goods" => Some(vec![1.5, 1.0]), // r, c
+        "volunteers_dilemma" => Some(vec![2.0, 1.0]), // b, c
+        "threshold" => Some(vec![2.0, 1.0, n_players.div_ceil(2) as f64]), // b, c, threshold
+        _ => None,
+    }
+}
+
+/// the games that are not restricted to 2 players
+fn is_group_game(game_name : &str) -> bool {
+    matches!(game_name, "public_goods" | "volunteers_dilemma" | "threshold")
+}
+
+impl GameBoard {
+
+    /// builds a named game from its parameters, in the order listed by default_params
+    pub fn from_params(game_name : &str, n_players : u32, params : &[f64]) -> Result<GameBoard, SimError> {
+        let expected = default_params(game_name, n_players)
+            .ok_or_else(|| SimError::InvalidParameter(format!("Invalid game name: {} not implemted yet", game_name)))?
+            .len();
+        if params.len() != expected {
+            return Err(SimError::InvalidParameter(format!("Invalid parameters for {}: got {} expected {}", game_name, params.len(), expected)));
+        }
+        if !is_group_game(game_name) && n_players != 2 {
+            return Err(SimError::InvalidParameter(format!("Invalid number of players: {} {} is a 2 player game", n_players, game_name)));
+        }
+
+        match game_name {
+            "prisoners_dilemma" => GameBoard::prisoners_dilemma(params[0], params[1], params[2], params[3]),
+            "hawk_dove" => GameBoard::hawk_dove(params[0], params[1]),
+            "snowdrift" => GameBoard::snowdrift(params[0], params[1]),
+            "stag_hunt" => GameBoard::stag_hunt(params[0], params[1], params[2], params[3]),
+            "chicken" => GameBoard::chicken(params[0], params[1], params[2], params[3]),
+            "harmony" => GameBoard::harmony(params[0], params[1], params[2], params[3]),
+            "battle_of_the_sexes" => GameBoard::battle_of_the_sexes(params[0], params[1]),
+            "matching_pennies" => GameBoard::matching_pennies(params[0]),
+            "rock_paper_scissors" => GameBoard::rock_paper_scissors(params[0], params[1], params[2]),
+            "optional_prisoners_dilemma" => GameBoard::optional_prisoners_dilemma(
+                params[0], params[1], params[2], params[3], params[4]
+            ),
+            "public_goods" => GameBoard::public_goods(n_players, params[0], params[1]),
+            "volunteers_dilemma" => GameBoard::volunteers_dilemma(n_players, params[0], params[1]),
+            "threshold" => {
+                // the threshold is a number of players, a float like 2.7 or -1 is not silently truncated
+                let threshold = params[2];
+                if threshold.fract() != 0.0 || threshold < 0.0 {
+                    return Err(SimError::InvalidParameter(
+                        format!("Invalid threshold: {} expected a non-negative whole number of players", threshold)
+                    ));
+                }
+                GameBoard::threshold(n_players, params[0], params[1], threshold as usize)
+            },
+            _ => Err(SimError::InvalidParameter(format!("Invalid game name: {} not implemted yet", game_name))),
+        }
+    }
+
+    /// symmetric 2 player game with no restriction on the ordering of T, R, P, S
+    pub fn symmetric(t : f64, r : f64, p : f64, s : f64) -> Result<GameBoard, SimError> {
+        let mut payoff_matrix : HashMap<Vec<Action>, Vec<f64>> = HashMap::new();
+        payoff_matrix.insert(vec![C, C], vec![r, r]);
+        payoff_matrix.insert(vec![C, D], vec![s, t]);
+        payoff_matrix.insert(vec![D, C], vec![t, s]);
+        payoff_matrix.insert(vec![D, D], vec![p, p]);
+        GameBoard::from_payoffs(payoff_matrix, 2, 2)
+    }
+
+    /// T > R > P > S
+    pub fn prisoners_dilemma(t : f64, r : f64, p : f64, s : f64) -> Result<GameBoard, SimError> {
+        if !(t > r && r > p && p > s) {
+            return Err(SimError::InvalidParameter(format!("prisoners_dilemma requires T > R > P > S got T={} R={} P={} S={}", t, r, p, s)));
+        }
+        GameBoard::symmetric(t, r, p, s)
+    }
+
+    /// T > R > S > P
+    pub fn chicken(t : f64, r : f64, p : f64, s : f64) -> Result<GameBoard, SimError> {
+        if !(t > r && r > s && s > p) {
+            return Err(SimError::InvalidParameter(format!("chicken requires T > R > S > P got T={} R={} P={} S={}", t, r, p, s)));
+        }
+        GameBoard::symmetric(t, r, p, s)
+    }
+
+    /// R > T >= P > S
+    pub fn stag_hunt(t : f64, r : f64, p : f64, s : f64) -> Result<GameBoard, SimError> {
+        if !(r > t && t >= p && p > s) {
+            return Err(SimError::InvalidParameter(format!("stag_hunt requires R > T >= P > S got T={} R={} P={} S={}", t, r, p, s)));
+        }
+        GameBoard::symmetric(t, r, p, s)
+    }
+
+    /// R > T and S > P, cooperation is dominant
+    pub fn harmony(t : f64, r : f64, p : f64, s : f64) -> Result<GameBoard, SimError> {
+        if !(r > t && s > p) {
+            return Err(SimError::InvalidParameter(format!("harmony requires R > T and S > P got T={} R={} P={} S={}", t, r, p, s)));
+        }
+        GameBoard::symmetric(t, r, p, s)
+    }
+
+    /// hawk dove with resource value V and cost of fighting C > V, dove is the cooperative action
+    pub fn hawk_dove(v : f64, c : f64) -> Result<GameBoard, SimError> {
+        if !(c > v && v > 0.0) {
+            return Err(SimError::InvalidParameter(format!("hawk_dove requires C > V > 0 got V={} C={}", v, c)));
+        }
+        GameBoard::symmetric(v, v / 2.0, (v - c) / 2.0, 0.0)
+    }
+
+    /// snowdrift with benefit b and cost c shared between cooperators, b > c > 0
+    pub fn snowdrift(b : f64, c : f64) -> Result<GameBoard, SimError> {
+        if !(b > c && c > 0.0) {
+            return Err(SimError::InvalidParameter(format!("snowdrift requires b > c > 0 got b={} c={}", b, c)));
+        }
+        GameBoard::symmetric(b, b - c / 2.0, 0.0, b - c)
+    }
+
+    /// coordination game where player 1 prefers both choosing 1 and player 2 both choosing 0
+    /// preferred > other > 0, miscoordination pays nothing
+    pub fn battle_of_the_sexes(preferred : f64, other : f64) -> Result<GameBoard, SimError> {
+        if !(preferred > other && other > 0.0) {
+            return Err(SimError::InvalidParameter(format!(
+                "battle_of_the_sexes requires preferred > other > 0 got preferred={} other={}", preferred, other
+            )));
+        }
+        let mut payoff_matrix : HashMap<Vec<Action>, Vec<f64>> = HashMap::new();
+        payoff_matrix.insert(vec![C, C], vec![preferred, other]);
+        payoff_matrix.insert(vec![C, D], vec![0.0, 0.0]);
+        payoff_matrix.insert(vec![D, C], vec![0.0, 0.0]);
+        payoff_matrix.insert(vec![D, D], vec![other, preferred]);
+        GameBoard::from_payoffs(payoff_matrix, 2, 2)
+    }
+
+    /// zero sum game, player 1 wins the stake when the actions match and player 2 when they differ
+    pub fn matching_pennies(stake : f64) -> Result<GameBoard, SimError> {
+        if stake <= 0.0 {
+            return Err(SimError::InvalidParameter(format!("matching_pennies requires a positive stake got {}", stake)));
+        }
+        let mut payoff_matrix : HashMap<Vec<Action>, Vec<f64>> = HashMap::new();
+        payoff_matrix.insert(vec![C, C], vec![stake, -stake]);
+        payoff_matrix.insert(vec![C, D], vec![-stake, stake]);
+        payoff_matrix.insert(vec![D, C], vec![-stake, stake]);
+        payoff_matrix.insert(vec![D, D], vec![stake, -stake]);
+        GameBoard::from_payoffs(payoff_matrix, 2, 2)
+    }
+
+    /// actions are 0 rock, 1 paper and 2 scissors, each action beats the one before it
+    pub fn rock_paper_scissors(win : f64, lose : f64, tie : f64) -> Result<GameBoard, SimError> {
+        if !(win > tie && tie > lose) {
+            return Err(SimError::InvalidParameter(format!("rock_paper_scissors requires win > tie > lose got win={} lose={} tie={}", win, lose, tie)));
+        }
+        let mut payoff_matrix : HashMap<Vec<Action>, Vec<f64>> = HashMap::new();
+        for a in 0..3 {
+            for b in 0..3 {
+                let payoff = match (3 + a - b) % 3 {
+                    0 => vec![tie, tie],
+                    1 => vec![win, lose],
+                    _ => vec![lose, win],
+                };
+                payoff_matrix.insert(vec![a, b], payoff);
+            }
+        }
+        GameBoard::from_payoffs(payoff_matrix, 2, 3)
+    }
+
+    /// prisoners dilemma with optional participation, action 2 abstains and both players
+    /// get the loner payoff whenever one of them abstains, P < loner < R
+    pub fn optional_prisoners_dilemma(t : f64, r : f64, p : f64, s : f64, loner : f64) -> Result<GameBoard, SimError> {
+        if !(r > loner && loner > p) {
+            return Err(SimError::InvalidParameter(format!("optional_prisoners_dilemma requires R > loner > P got R={} loner={} P={}", r, loner, p)));
+        }
+        const ABSTAIN : Action = 2;
+        let mut payoff_matrix = GameBoard::prisoners_dilemma(t, r, p, s)?.payoff_matrix();
+        for other in [C, D, ABSTAIN] {
+            payoff_matrix.insert(vec![ABSTAIN, other], vec![loner, loner]);
+            payoff_matrix.insert(vec![other, ABSTAIN], vec![loner, loner]);
+        }
+        GameBoard::from_payoffs(payoff_matrix, 2, 3)
+    }
+
+    /// generates the full 2^n table for a game where the payoff of a player only depends on
+    /// its own action and the number of cooperators in the group
+    /// # Args:
+    ///     n_players: u32 - the group size
+    ///     payoff: Fn(bool, usize) -> f64 - payoff given (cooperates, number of cooperators including itself)
+    pub fn from_cooperator_count<F>(n_players : u32, payoff : F) -> Result<GameBoard, SimError>
+    where
+        F: Fn(bool, usize) -> f64,
+    {
+        if !(2..=MAX_GROUP_SIZE).contains(&n_players) {
+            return Err(SimError::InvalidParameter(
+                format!("Invalid number of players: {} expected 2..={}", n_players, MAX_GROUP_SIZE)
+            ));
+        }
+        let n = n_players as usize;
+        let mut payoff_matrix : HashMap<Vec<Action>, Vec<f64>> = HashMap::new();
+        for profile in 0..2usize.pow(n_players) {
+            let action : Vec<Action> = (0..n).rev()
+                .map(|bit| if (profile >> bit) & 1 == 1 { C } else { D })
+                .collect();
+            let n_cooperators = action.iter().filter(|&&a| a == C).count();
+            let payoffs = action.iter().map(|&a| payoff(a == C, n_cooperators)).collect();
+            payoff_matrix.insert(action, payoffs);
+        }
+        GameBoard::from_payoffs(payoff_matrix, n_players, 2)
+    }
+
+    /// every cooperator puts c into a pot that is multiplied by r and shared equally by the group,
+    /// it is a social dilemma when 1 < r < n_players
+    pub fn public_goods(n_players : u32, r : f64, c : f64) -> Result<GameBoard, SimError> {
+        if !(r > 0.0 && c > 0.0) {
+            return Err(SimError::InvalidParameter(format!("public_goods requires r > 0 and c > 0 got r={} c={}", r, c)));
+        }
+        let n = n_players as f64;
+        GameBoard::from_cooperator_count(n_players, |cooperates, n_cooperators| {
+            let share = r * c * n_cooperators as f64 / n;
+            if cooperates { share - c } else { share }
+        })
+    }
+
+    /// everyone gets b if at least one player volunteers, volunteers pay c with b > c > 0
+    pub fn volunteers_dilemma(n_players : u32, b : f64, c : f64) -> Result<GameBoard, SimError> {
+        GameBoard::threshold(n_players, b, c, 1)
+    }
+
+    /// everyone gets b if at least threshold players cooperate, cooperators pay c with b > c > 0
+    pub fn threshold(n_players : u32, b : f64, c : f64, threshold : usize) -> Result<GameBoard, SimError> {
+        if !(b > c && c > 0.0) {
+            return Err(SimError::InvalidParameter(format!("threshold games require b > c > 0 got b={} c={}", b, c)));
+        }
+        if threshold < 1 || threshold > n_players as usize {
+            return Err(SimError::InvalidParameter(format!("Invalid threshold: {} expected 1..={}", threshold, n_players)));
+        }
+        GameBoard::from_cooperator_count(n_players, |cooperates, n_cooperators| {
+            let benefit = if n_cooperators >= threshold { b } else { 0.0 };
+            if cooperates { benefit - c } else { benefit }
+        })
+    }
+}
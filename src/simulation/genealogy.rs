@@ -0,0 +1,161 @@
+use serde::{Serialize, Deserialize};
+use polars::prelude::*;
+use std::{collections::{BTreeMap, HashMap}, fs::File};
+use crate::simulation::{
+    genotype::{Genotype, MutationKind},
+    error::SimError,
+};
+
+/// a genotype of the tree, the first time it appeared in the population
+/// # Variables:
+///     pub genotype_id: u64 - Genotype::genotype_id
+///     pub agent_id: String - Genotype::id, used as the label of the node
+///     pub parent_id: Option<u64> - the genotype it mutated from, None for the initial population
+///     pub mutations: Vec<MutationKind> - the mutations that turned the parent into this genotype in one step
+///     pub round_number: u32 - the round in which it appeared
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GenealogyNode {
+    pub genotype_id : u64,
+    pub agent_id : String,
+    pub parent_id : Option<u64>,
+    pub mutations : Vec<MutationKind>,
+    pub round_number : u32,
+}
+
+/// records the parent of every new genotype of a run, set Game::genealogy to Some(Genealogy::new()) before run.
+/// Only the first appearance of a genotype is kept, a genotype that arises again from another parent keeps
+/// its first parent as the GenotypeRegistry of the run does.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Genealogy {
+    nodes : BTreeMap<u64, GenealogyNode>,
+}
+
+impl Genealogy {
+    pub fn new() -> Genealogy {
+        Genealogy::default()
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &GenealogyNode> {
+        self.nodes.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// true when the genotype has a node in the tree
+    pub fn contains(&self, genotype : &Genotype) -> bool {
+        self.nodes.contains_key(&genotype.genotype_id())
+    }
+
+    /// adds a genotype of the initial population
+    pub fn add_root(&mut self, genotype : &Genotype, round_number : u32) {
+        self.nodes.entry(genotype.genotype_id()).or_insert_with(|| GenealogyNode {
+            genotype_id: genotype.genotype_id(),
+            agent_id: genotype.id.clone(),
+            parent_id: None,
+            mutations: Vec::new(),
+            round_number,
+        });
+    }
+
+    /// adds child as a descendant of parent if the genotype of child is new
+    pub fn record(&mut self, parent : &Genotype, child : &Genotype, mutations : &[MutationKind], round_number : u32) {
+        let parent_id = parent.genotype_id();
+        let child_id = child.genotype_id();
+        if parent_id == child_id {
+            return;
+        }
+        self.nodes.entry(child_id).or_insert_with(|| GenealogyNode {
+            genotype_id: child_id,
+            agent_id: child.id.clone(),
+            parent_id: Some(parent_id),
+            mutations: mutations.to_vec(),
+            round_number,
+        });
+    }
+
+    /// one row per parent to child edge in the order the children appeared,
+    /// the mutation column joins the mutation kinds with "+"
+    pub fn edges_to_dataframe(&self) -> Result<DataFrame, SimError> {
+        let mut edges : Vec<&GenealogyNode> = self.nodes.values().filter(|node| node.parent_id.is_some()).collect();
+        edges.sort_by_key(|node| (node.round_number, node.genotype_id));
+        let mutation_names : Vec<String> = edges.iter()
+            .map(|node| node.mutations.iter().map(|kind| format!("{:?}", kind).to_lowercase()).collect::<Vec<_>>().join("+"))
+            .collect();
+        Ok(DataFrame::new(vec![
+            Series::new("parent_genotype_id", edges.iter().map(|node| node.parent_id).collect::<Vec<Option<u64>>>()),
+            Series::new("child_genotype_id", edges.iter().map(|node| node.genotype_id).collect::<Vec<u64>>()),
+            Series::new("child_agent_id", edges.iter().map(|node| node.agent_id.as_str()).collect::<Vec<&str>>()),
+            Series::new("mutation", mutation_names),
+            Series::new("round_number", edges.iter().map(|node| node.round_number).collect::<Vec<u32>>()),
+        ])?)
+    }
+
+    pub fn dump_edges_to_parquet(&self, file_path : &str) -> Result<(), SimError> {
+        let mut df = self.edges_to_dataframe()?;
+        ParquetWriter::new(File::create(file_path)?).finish(&mut df)?;
+        Ok(())
+    }
+
+    /// the tree in Newick format, nodes are labelled with the agent id and the branch lengths are
+    /// the number of rounds between the birth of the parent and of the child.
+    /// Genotypes whose parent is not recorded are roots, several roots hang from an unlabelled node.
+    pub fn to_newick(&self) -> String {
+        let mut children : HashMap<u64, Vec<u64>> = HashMap::new();
+        let mut roots : Vec<u64> = Vec::new();
+        for node in self.nodes.values() {
+            match node.parent_id.filter(|parent| self.nodes.contains_key(parent)) {
+                Some(parent) => children.entry(parent).or_default().push(node.genotype_id),
+                None => roots.push(node.genotype_id),
+            }
+        }
+
+        // the trees can be as deep as the run is long, so they are written without recursion
+        let mut subtrees : HashMap<u64, String> = HashMap::new();
+        for &root in &roots {
+            let mut stack : Vec<(u64, bool)> = vec![(root, false)];
+            while let Some((id, expanded)) = stack.pop() {
+                let node_children = children.get(&id).map(Vec::as_slice).unwrap_or(&[]);
+                if !expanded {
+                    stack.push((id, true));
+                    stack.extend(node_children.iter().map(|&child| (child, false)));
+                    continue;
+                }
+                let node = &self.nodes[&id];
+                let mut subtree = String::new();
+                if !node_children.is_empty() {
+                    let inner : Vec<String> = node_children.iter().map(|child| {
+                        // a parent recorded by an earlier run may be younger than the child, the branch is then 0
+                        let length = self.nodes[child].round_number.saturating_sub(node.round_number);
+                        format!("{}:{}", subtrees.remove(child).unwrap_or_default(), length)
+                    }).collect();
+                    subtree.push('(');
+                    subtree.push_str(&inner.join(","));
+                    subtree.push(')');
+                }
+                subtree.push_str(&node.agent_id);
+                subtrees.insert(id, subtree);
+            }
+        }
+
+        match roots.as_slice() {
+            [root] => format!("{};", subtrees.remove(root).unwrap_or_default()),
+            _ => {
+                let inner : Vec<String> = roots.iter().map(|root| {
+                    format!("{}:{}", subtrees.remove(root).unwrap_or_default(), self.nodes[root].round_number)
+                }).collect();
+                format!("({});", inner.join(","))
+            },
+        }
+    }
+
+    pub fn dump_to_newick(&self, file_path : &str) -> Result<(), SimError> {
+        std::fs::write(file_path, self.to_newick())?;
+        Ok(())
+    }
+}
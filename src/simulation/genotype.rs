@@ -0,0 +1,370 @@
+use rand::Rng;
+use serde::{Serialize, Deserialize, Deserializer};
+use serde::ser::{Serializer, SerializeStruct};
+use serde::de;
+use rand_distr::{Distribution, Poisson};
+use crate::simulation::{error::SimError, packed::PackedGenome, strategy::{self, Strategy}, types::{action_to_digit, Action, MAX_ACTIONS}};
+use std::hash::{Hash, Hasher};
+
+/// the heritable part of an agent, the key of every population map.
+/// Two genotypes are equal when their genomes are, the id and the lineage only describe them.
+/// The history is not part of it, every Agent playing the genotype has its own.
+/// # Variables:
+///     pub id: String - Genotype::genome_to_id of the genome
+///     pub memory_len: usize - m, the number of past actions the genome reads
+///     pub genome: PackedGenome - the strategy, k^m genes indexed by the history read as a base k number
+///     pub n_actions: usize - k the size of the action alphabet
+///     pub parent_id: Option<u64> - genotype_id of the genotype this one mutated from, None for the initial population
+///     pub birth_round: u32 - the round in which the genotype first appeared
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "SerializedGenotype")]
+pub struct Genotype {
+    pub id : String,
+    pub memory_len : usize,
+    pub genome : PackedGenome,
+    pub n_actions : usize,
+    pub parent_id : Option<u64>,
+    pub birth_round : u32,
+}
+
+/// the json layout of a Genotype, the genome is a list of actions that is packed once the alphabet is known.
+/// The id in the file is not read, it is rebuilt from the genome so a stale id can not end up in the registry
+#[derive(Deserialize)]
+struct SerializedGenotype {
+    memory_len : usize,
+    #[serde(deserialize_with = "deserialize_actions")]
+    genome : Vec<Action>,
+    #[serde(default = "default_n_actions")]
+    n_actions : usize,
+    #[serde(default)]
+    parent_id : Option<u64>,
+    #[serde(default)]
+    birth_round : u32,
+}
+
+impl TryFrom<SerializedGenotype> for Genotype {
+    type Error = SimError;
+
+    fn try_from(serialized : SerializedGenotype) -> Result<Genotype, SimError> {
+        Genotype::check_n_actions(serialized.n_actions)?;
+        if let Some(&action) = serialized.genome.iter().find(|&&a| a as usize >= serialized.n_actions) {
+            return Err(SimError::GenomeMismatch(
+                format!("Invalid action {} expected an action below {}", action, serialized.n_actions)
+            ));
+        }
+        let genome_len = Genotype::genome_len(serialized.memory_len, serialized.n_actions)?;
+        if serialized.genome.len() != genome_len {
+            return Err(SimError::GenomeMismatch(format!(
+                "Genome of {} genes expected {}^{} = {} genes",
+                serialized.genome.len(), serialized.n_actions, serialized.memory_len, genome_len
+            )));
+        }
+        let genome = PackedGenome::from_actions(&serialized.genome, serialized.n_actions);
+        Ok(Genotype {
+            id : genome.to_id(),
+            memory_len : serialized.memory_len,
+            genome,
+            n_actions : serialized.n_actions,
+            parent_id : serialized.parent_id,
+            birth_round : serialized.birth_round,
+        })
+    }
+}
+
+/// the kinds of mutation a genotype can go through, as in Lindgren's model
+/// # Variants:
+///     Point - one gene changes to another action
+///     Duplication - the genome is copied k times and the memory grows by one, the behaviour is unchanged
+///     Split - one of the k blocks of the genome is kept and the memory shrinks by one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum MutationKind {
+    Point,
+    Duplication,
+    Split,
+}
+
+fn default_n_actions() -> usize {
+    2
+}
+
+/// reads a list of actions, older runs wrote 2 action genomes and histories as booleans
+pub(crate) fn deserialize_actions<'de, D>(deserializer: D) -> Result<Vec<Action>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ActionOrBool {
+        Action(Action),
+        Bool(bool),
+    }
+
+    let values : Vec<ActionOrBool> = Vec::deserialize(deserializer)
+        .map_err(|e| de::Error::custom(format!("expected a list of actions: {}", e)))?;
+    Ok(values.into_iter().map(|v| match v {
+        ActionOrBool::Action(a) => a,
+        ActionOrBool::Bool(b) => b as Action,
+    }).collect())
+}
+
+/// the number of fields written by Genotype::serialize_fields
+pub(crate) const SERIALIZED_FIELDS : usize = 6;
+
+impl Serialize for Genotype {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("Genotype", SERIALIZED_FIELDS)?;
+        self.serialize_fields(&mut state)?;
+        state.end()
+    }
+}
+
+impl PartialEq for Genotype {
+    fn eq(&self, other: &Self) -> bool {
+        self.genome == other.genome
+    }
+}
+
+impl Eq for Genotype {}
+
+// hashes what PartialEq compares, otherwise the population maps depend on the hasher state
+// and a seeded run could not be reproduced
+impl Hash for Genotype {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.genome.hash(state);
+    }
+}
+
+impl Ord for Genotype {
+    //we order by id
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.id.cmp(&other.id)
+    }
+}
+
+impl PartialOrd for Genotype {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Genotype {
+    /// the genome must have k^memory_len genes, all below k
+    pub fn new(genome : Vec<Action>, memory_len : u32, n_actions : u32) -> Result<Genotype, SimError> {
+        let (memory_len, n_actions) = (memory_len as usize, n_actions as usize);
+        Genotype::check_n_actions(n_actions)?;
+        let genome_len = Genotype::genome_len(memory_len, n_actions)?;
+        if genome.len() != genome_len {
+            return Err(SimError::GenomeMismatch(format!(
+                "Strategy and memory length mismatch got genome len {} and memory len {} \
+                expected genome len of {}^memory len {}",
+                genome.len(), memory_len, n_actions, genome_len
+            )));
+        }
+        if let Some(&action) = genome.iter().find(|&&a| a as usize >= n_actions) {
+            return Err(SimError::GenomeMismatch(
+                format!("Invalid action {} expected an action below {}", action, n_actions)
+            ));
+        }
+        let genome = PackedGenome::from_actions(&genome, n_actions);
+        Ok(Genotype {
+            id : genome.to_id(),
+            memory_len,
+            genome,
+            n_actions,
+            parent_id: None,
+            birth_round: 0,
+        })
+    }
+
+    /// writes the fields of the genotype into a struct being serialized, the Agent json adds its history to them
+    pub(crate) fn serialize_fields<S: SerializeStruct>(&self, state : &mut S) -> Result<(), S::Error> {
+        state.serialize_field("id", &self.id)?;
+        state.serialize_field("memory_len", &self.memory_len)?;
+        state.serialize_field("genome", &self.genome)?;
+        state.serialize_field("n_actions", &self.n_actions)?;
+        state.serialize_field("parent_id", &self.parent_id)?;
+        state.serialize_field("birth_round", &self.birth_round)
+    }
+
+    /// a genotype needs at least 2 actions, a point mutation moves a gene to one of the other k - 1 actions,
+    /// and at most MAX_ACTIONS so every gene is one digit of the id
+    pub(crate) fn check_n_actions(n_actions : usize) -> Result<(), SimError> {
+        if !(2..=MAX_ACTIONS).contains(&n_actions) {
+            return Err(SimError::InvalidParameter(
+                format!("Invalid number of actions: {} expected 2..={}", n_actions, MAX_ACTIONS)
+            ));
+        }
+        Ok(())
+    }
+
+    /// k^memory_len, the number of genes of a genome, an error when it does not fit in a usize
+    pub fn genome_len(memory_len : usize, n_actions : usize) -> Result<usize, SimError> {
+        u32::try_from(memory_len).ok()
+            .and_then(|memory_len| n_actions.checked_pow(memory_len))
+            .ok_or_else(|| SimError::InvalidParameter(
+                format!("Invalid memory length: {}^{} genes do not fit in memory", n_actions, memory_len)
+            ))
+    }
+
+    /// random genome over an alphabet of n_actions drawn from the given rng
+    pub fn random_from_rng<R: Rng + ?Sized>(memory_len : u32, n_actions : u32, rng : &mut R) -> Result<Genotype, SimError> {
+        Genotype::check_n_actions(n_actions as usize)?;
+        let genome : Vec<Action> = (0..Genotype::genome_len(memory_len as usize, n_actions as usize)?)
+            .map(|_| rng.gen_range(0..n_actions) as Action)
+            .collect();
+        let genome = PackedGenome::from_actions(&genome, n_actions as usize);
+        Ok(Genotype {
+            id : genome.to_id(),
+            memory_len : memory_len as usize,
+            genome,
+            n_actions: n_actions as usize,
+            parent_id: None,
+            birth_round: 0,
+        })
+    }
+
+    /// one base 36 digit per gene, for 2 action games this is the bit string of the genome
+    pub fn genome_to_id(genome : &[Action]) -> String {
+        genome.iter()
+            .map(|&action| action_to_digit(action))
+            .collect()
+     }
+
+    /// the action the genome plays after the history read as a base k number, see Agent::get_action
+    #[inline]
+    pub fn action(&self, history_index : usize) -> Action {
+        self.genome.get(history_index)
+    }
+
+    /// the history register after the actions are added to it, oldest first, see Agent::add_memory.
+    /// The register is the history read as a base k number, the digits above k^m are the actions that are forgotten
+    #[inline]
+    pub fn push_history(&self, history : usize, actions : &[Action]) -> usize {
+        let states = self.genome.len();
+        actions.iter().fold(history, |history, &action| (history * self.n_actions + action as usize) % states)
+    }
+
+    /// a numeric id of the genotype that is the same in every run and on every platform,
+    /// the 64 bit FNV-1a hash of the alphabet size and the genome
+    pub fn genotype_id(&self) -> u64 {
+        const OFFSET : u64 = 0xcbf29ce484222325;
+        const PRIME : u64 = 0x100000001b3;
+        std::iter::once(self.n_actions as u8).chain(self.genome.iter())
+            .fold(OFFSET, |hash, byte| (hash ^ byte as u64).wrapping_mul(PRIME))
+    }
+
+    /// the name of the strategy the genome plays in a game of n_players, see Strategy::classify
+    pub fn strategy(&self, n_players : u32) -> Option<Strategy> {
+        Strategy::classify(&self.genome.to_actions(), self.memory_len, self.n_actions, n_players)
+    }
+
+    /// the behaviourally equivalent genotype with the shortest memory, the duplications are undone.
+    /// The lineage is kept.
+    pub fn canonical(&self) -> Genotype {
+        let (genome, memory_len) = strategy::minimal_genome(&self.genome.to_actions(), self.memory_len, self.n_actions);
+        if memory_len == self.memory_len {
+            return self.clone();
+        }
+        let genome = PackedGenome::from_actions(&genome, self.n_actions);
+        Genotype {
+            id: genome.to_id(),
+            memory_len,
+            genome,
+            n_actions: self.n_actions,
+            parent_id: self.parent_id,
+            birth_round: self.birth_round,
+        }
+    }
+
+    /// true when the genome can not be shortened without changing the behaviour
+    pub fn is_canonical(&self) -> bool {
+        strategy::minimal_genome(&self.genome.to_actions(), self.memory_len, self.n_actions).1 == self.memory_len
+    }
+
+    /// records that this is a new genotype that mutated from parent in the given round
+    pub fn set_parent(&mut self, parent : &Genotype, round_number : u32) {
+        self.parent_id = Some(parent.genotype_id());
+        self.birth_round = round_number;
+    }
+
+    /// returns the kinds of the mutations that were applied, empty when the genotype did not mutate
+    pub fn mutate<R: Rng + ?Sized>(&mut self,  p_p : f64, p_d : f64, p_s : f64, rng : &mut R) -> Vec<MutationKind> {
+        match self.mutant(p_p, p_d, p_s, rng) {
+            Some((mutant, applied)) => {
+                *self = mutant;
+                applied
+            },
+            None => Vec::new(),
+        }
+    }
+
+    /// draws the mutations like mutate but leaves the genotype as it is, it is only cloned when a mutation happens
+    /// # Returns:
+    ///     Option<(Genotype, Vec<MutationKind>)> - the mutant and the applied mutations, None when nothing was applied
+    pub fn mutant<R: Rng + ?Sized>(&self,  p_p : f64, p_d : f64, p_s : f64, rng : &mut R) -> Option<(Genotype, Vec<MutationKind>)> {
+
+        //sample 3 one for each mutation type
+        let mut mutant : Option<Genotype> = None;
+        let mut applied = Vec::new();
+
+        for (kind, rate) in [
+            (MutationKind::Point, p_p),
+            (MutationKind::Duplication, p_d),
+            (MutationKind::Split, p_s),
+        ] {
+            if rate <= 0.0 {
+                continue;
+            }
+            let Ok(poisson) = Poisson::new(rate) else {
+                continue;
+            };
+            if poisson.sample(rng) > 0.0 && mutant.get_or_insert_with(|| self.clone()).apply_mutation(kind, rng) {
+                applied.push(kind);
+            }
+        }
+        mutant.filter(|_| !applied.is_empty()).map(|mutant| (mutant, applied))
+    }
+
+    /// applies one mutation of the given kind and updates the id,
+    /// returns false when the mutation is not possible (a split of a genotype without memory
+    /// or a point mutation of a one action genome)
+    pub fn apply_mutation<R: Rng + ?Sized>(&mut self, kind : MutationKind, rng : &mut R) -> bool {
+        match kind {
+            MutationKind::Point => {
+                if self.n_actions < 2 {
+                    return false;
+                }
+                let index = rng.gen_range(0..self.genome.len());
+                // move to one of the k - 1 other actions
+                let shift = rng.gen_range(1..self.n_actions) as Action;
+                self.genome.set(index, (self.genome.get(index) + shift) % self.n_actions as Action);
+            },
+            MutationKind::Duplication => {
+                // the new oldest action is the most significant digit of the index,
+                // repeating the genome k times makes the genotype ignore it
+                self.genome = self.genome.repeat(self.n_actions);
+                self.memory_len += 1;
+            },
+            MutationKind::Split => {
+                if self.memory_len == 0 {
+                    return false;
+                }
+                // keep the block of one value of the oldest action and forget that action
+                let block = self.genome.len() / self.n_actions;
+                let start = rng.gen_range(0..self.n_actions) * block;
+                self.genome = self.genome.slice(start, block);
+                self.memory_len -= 1;
+            },
+        }
+        self.id = self.genome.to_id();
+        true
+    }
+
+    /// checks that the genome still has k^memory_len genes
+    pub fn is_consistent(&self) -> bool {
+        Genotype::genome_len(self.memory_len, self.n_actions).is_ok_and(|genome_len| genome_len == self.genome.len())
+    }
+}
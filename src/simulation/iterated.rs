@@ -0,0 +1,379 @@
+use itertools::Itertools;
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use crate::simulation::{agent::{self, Agent}, error::SimError, genotype::Genotype, types::{Action, GameBoard}};
+
+/// continuation weights below this are dropped when discounting
+const MIN_WEIGHT : f64 = 1e-9;
+/// the analytic mode refuses games with more joint memory states than this
+const MAX_STATES : usize = 1 << 20;
+/// the noisy infinite game iterates the state distribution until it moves less than this
+const STATIONARY_TOLERANCE : f64 = 1e-12;
+const MAX_ITERATIONS : usize = 100_000;
+
+/// how long the agents of one group play each other
+/// # Variants:
+///     Rounds(n) - n >= 1 moves starting from the agents' stored histories, the payoff is the mean over the moves
+///     Continuation(w) - after every move the game goes on with probability w, the payoff is the expected
+///         payoff per move (1 - w) * sum_t w^t * payoff_t, truncated once w^t is negligible
+///     Infinite - the long run mean payoff of the infinitely iterated game, found by solving the Markov chain
+///         over the joint memory states and averaging over all starting states
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum GameLength {
+    Rounds(u32),
+    Continuation(f64),
+    Infinite,
+}
+
+impl Default for GameLength {
+    /// a single move, the behaviour before iterated games were played
+    fn default() -> Self {
+        GameLength::Rounds(1)
+    }
+}
+
+/// the trembling hand error rates of a game
+/// # Variables:
+///     pub execution: f64 - probability that a player plays another action than its genome says
+///     pub perception: f64 - probability that a player stores another action than an opponent played
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Noise {
+    pub execution : f64,
+    pub perception : f64,
+}
+
+impl Noise {
+    pub fn is_noiseless(&self) -> bool {
+        self.execution <= 0.0 && self.perception <= 0.0
+    }
+
+    /// both rates are probabilities, outside [0, 1] the tremble distribution has negative entries
+    pub fn check(&self) -> Result<(), SimError> {
+        for (name, rate) in [("execution", self.execution), ("perception", self.perception)] {
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(SimError::InvalidParameter(
+                    format!("Invalid {} error: {} expected a probability in [0, 1]", name, rate)
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// plays one group of agents once in every seating of the payoff table and averages the payoff of each agent,
+/// so in asymmetric games the score of an agent does not depend on the seat its position in the group gives it
+/// # Args:
+///     board: &GameBoard - the stage game
+///     players: &[&Agent] - the group in any order
+///     length: GameLength - how many moves are played
+///     noise: Noise - the execution and perception error rates
+///     rng: &mut R - the source of the errors, the seatings draw from it one after the other
+/// # Returns:
+///     Result<Vec<f64>, SimError> - the mean payoff per move of each player over the n_players! seatings
+pub fn play_all_seatings<R: Rng + ?Sized>(
+    board : &GameBoard, players : &[&Agent], length : GameLength, noise : Noise, rng : &mut R
+) -> Result<Vec<f64>, SimError> {
+    let mut scores = vec![0.0; players.len()];
+    let mut n_seatings = 0;
+    for seating in (0..players.len()).permutations(players.len()) {
+        // seat s is taken by player seating[s]
+        let seated : Vec<&Agent> = seating.iter().map(|&player| players[player]).collect();
+        let seat_scores = play(board, &seated, length, noise, rng)?;
+        for (&player, score) in seating.iter().zip(seat_scores) {
+            scores[player] += score;
+        }
+        n_seatings += 1;
+    }
+    Ok(scores.into_iter().map(|score| score / n_seatings as f64).collect())
+}
+
+/// plays one group of agents against each other
+/// # Args:
+///     board: &GameBoard - the stage game
+///     players: &[&Agent] - the group, players[i] takes the ith seat of the payoff table
+///     length: GameLength - how many moves are played
+///     noise: Noise - the execution and perception error rates
+///     rng: &mut R - the source of the errors, unused by noiseless games
+/// # Returns:
+///     Result<Vec<f64>, SimError> - the mean payoff per move of each player
+pub fn play<R: Rng + ?Sized>(
+    board : &GameBoard, players : &[&Agent], length : GameLength, noise : Noise, rng : &mut R
+) -> Result<Vec<f64>, SimError> {
+    noise.check()?;
+    match length {
+        GameLength::Rounds(n_rounds) => {
+            if n_rounds == 0 {
+                return Err(SimError::InvalidParameter("Invalid number of rounds: 0 expected at least 1".to_string()));
+            }
+            play_weighted(board, players, (0..n_rounds).map(|_| 1.0), noise, rng)
+        },
+        GameLength::Continuation(w) => {
+            if !(0.0..1.0).contains(&w) {
+                return Err(SimError::InvalidParameter(
+                    format!("Invalid continuation probability: {} expected a value in [0, 1)", w)
+                ));
+            }
+            play_weighted(board, players, std::iter::successors(Some(1.0), |weight| Some(weight * w))
+                .take_while(|&weight| weight > MIN_WEIGHT), noise, rng)
+        },
+        GameLength::Infinite => stationary_payoffs(board, players, noise),
+    }
+}
+
+/// plays the moves one after the other, move t counts with weight weights[t].
+/// The agents are only read, every player is its genotype and a copy of its history register
+fn play_weighted<I, R>(
+    board : &GameBoard, players : &[&Agent], weights : I, noise : Noise, rng : &mut R
+) -> Result<Vec<f64>, SimError>
+where
+    I: Iterator<Item = f64>,
+    R: Rng + ?Sized,
+{
+    let n_players = players.len();
+    let genotypes : Vec<&Genotype> = players.iter().map(|agent| &agent.genotype).collect();
+    let mut histories : Vec<usize> = players.iter().map(|agent| agent.history_index()).collect();
+    let mut total = vec![0.0; n_players];
+    let mut total_weight = 0.0;
+    let mut actions : Vec<Action> = vec![0; n_players];
+    let mut perceived : Vec<Action> = Vec::with_capacity(n_players);
+
+    if n_players != board.n_players as usize {
+        return Err(SimError::InvalidPayoffTable(
+            format!("Invalid action length: {} expected {}", n_players, board.n_players)
+        ));
+    }
+    let k = board.n_actions as usize;
+
+    for weight in weights {
+        // the profile index is packed while the actions are drawn, the payoff is a slice of the board
+        let mut profile = 0;
+        for ((action, genotype), &history) in actions.iter_mut().zip(genotypes.iter()).zip(histories.iter()) {
+            *action = agent::tremble(genotype.action(history), genotype.n_actions, noise.execution, rng);
+            profile = profile * k + *action as usize;
+        }
+        let payoff = board.payoff(profile)?;
+        for (sum, value) in total.iter_mut().zip(payoff.iter()) {
+            *sum += weight * value;
+        }
+        total_weight += weight;
+
+        for (i, (genotype, history)) in genotypes.iter().zip(histories.iter_mut()).enumerate() {
+            // the player always knows its own action, every opponent action is misperceived on its own
+            perceived.clear();
+            perceived.push(actions[i]);
+            perceived.extend(actions.iter().enumerate().filter(|&(j, _)| j != i)
+                .map(|(_, &a)| agent::tremble(a, genotype.n_actions, noise.perception, rng)));
+            *history = genotype.push_history(*history, &perceived);
+        }
+    }
+
+    Ok(total.iter().map(|sum| sum / total_weight).collect())
+}
+
+/// the joint state of a group is the concatenation of every player's full history,
+/// read as one base k number with player 0 as the most significant part
+struct JointState<'a> {
+    players : &'a [&'a Agent],
+    n_states : usize,
+}
+
+impl<'a> JointState<'a> {
+    fn new(players : &'a [&'a Agent], k : usize) -> Result<JointState<'a>, SimError> {
+        let total_memory : u32 = players.iter().map(|agent| agent.genotype.memory_len as u32).sum();
+        let n_states = k.checked_pow(total_memory)
+            .filter(|&n| n <= MAX_STATES)
+            .ok_or_else(|| SimError::InvalidParameter(format!(
+                "Too many joint memory states for the infinite game: {}^{} exceeds {}", k, total_memory, MAX_STATES
+            )))?;
+        Ok(JointState { players, n_states })
+    }
+
+    /// the history index of every player, the history of player i is its memory_len digits of the state
+    fn decode(&self, state : usize) -> Vec<usize> {
+        let mut rest = state;
+        let mut histories : Vec<usize> = self.players.iter().rev().map(|agent| {
+            let states = agent.genotype.genome.len();
+            let history = rest % states;
+            rest /= states;
+            history
+        }).collect();
+        histories.reverse();
+        histories
+    }
+
+    fn encode(&self, histories : &[usize]) -> usize {
+        self.players.iter().zip(histories.iter())
+            .fold(0, |acc, (agent, &history)| acc * agent.genotype.genome.len() + history)
+    }
+}
+
+/// the probability of every action when `intended` is played with an error rate spread over the other actions
+fn tremble_distribution(intended : Action, error : f64, k : usize) -> Vec<f64> {
+    (0..k).map(|a| if a == intended as usize { 1.0 - error } else { error / (k - 1) as f64 }).collect()
+}
+
+/// every vector of n base k digits with its probability when digit i follows distributions[i]
+fn product_distribution(distributions : &[Vec<f64>], k : usize) -> Vec<(Vec<Action>, f64)> {
+    let mut outcomes : Vec<(Vec<Action>, f64)> = vec![(Vec::new(), 1.0)];
+    for distribution in distributions {
+        outcomes = outcomes.into_iter().flat_map(|(prefix, p)| {
+            (0..k).filter(|&a| distribution[a] > 0.0).map(move |a| {
+                let mut actions = prefix.clone();
+                actions.push(a as Action);
+                (actions, p * distribution[a])
+            })
+        }).collect();
+    }
+    outcomes
+}
+
+/// long run payoff of the infinitely repeated game.
+/// Without noise every joint state has exactly one successor, so every starting state ends in a cycle
+/// and its long run payoff is the mean over that cycle. The result is the mean over all starting states.
+/// With noise the state distribution is iterated from the uniform distribution until it is stationary.
+fn stationary_payoffs(board : &GameBoard, players : &[&Agent], noise : Noise) -> Result<Vec<f64>, SimError> {
+    if noise.is_noiseless() {
+        return deterministic_stationary_payoffs(board, players);
+    }
+    let n_players = players.len();
+    let k = board.n_actions as usize;
+    let joint = JointState::new(players, k)?;
+
+    // the transitions of every state and its expected payoff
+    let mut transitions : Vec<Vec<(usize, f64)>> = Vec::with_capacity(joint.n_states);
+    let mut state_payoff : Vec<Vec<f64>> = Vec::with_capacity(joint.n_states);
+    let genotypes : Vec<&Genotype> = players.iter().map(|agent| &agent.genotype).collect();
+    for state in 0..joint.n_states {
+        let histories = joint.decode(state);
+        let intended : Vec<Action> = genotypes.iter().zip(histories.iter())
+            .map(|(genotype, &history)| genotype.action(history))
+            .collect();
+        let executed = product_distribution(
+            &intended.iter().map(|&a| tremble_distribution(a, noise.execution, k)).collect::<Vec<_>>(), k
+        );
+
+        let mut expected = vec![0.0; n_players];
+        let mut next : HashMap<usize, f64> = HashMap::new();
+        for (actions, p_executed) in executed {
+            for (sum, value) in expected.iter_mut().zip(board.get_payoff(&actions)?.iter()) {
+                *sum += p_executed * value;
+            }
+            // every player perceives the opponents independently, its own action is always known
+            let mut per_player : Vec<Vec<(usize, f64)>> = Vec::with_capacity(n_players);
+            for (i, (genotype, &history)) in genotypes.iter().zip(histories.iter()).enumerate() {
+                let opponents : Vec<Vec<f64>> = actions.iter().enumerate()
+                    .filter(|&(j, _)| j != i)
+                    .map(|(_, &a)| tremble_distribution(a, noise.perception, k))
+                    .collect();
+                per_player.push(product_distribution(&opponents, k).into_iter().map(|(seen, p)| {
+                    let mut perceived = vec![actions[i]];
+                    perceived.extend(seen);
+                    (genotype.push_history(history, &perceived), p)
+                }).collect());
+            }
+            let mut joint_next : Vec<(Vec<usize>, f64)> = vec![(Vec::new(), p_executed)];
+            for outcomes in per_player {
+                joint_next = joint_next.into_iter().flat_map(|(prefix, p)| {
+                    outcomes.iter().map(move |(history, q)| {
+                        let mut histories = prefix.clone();
+                        histories.push(*history);
+                        (histories, p * q)
+                    }).collect::<Vec<_>>()
+                }).collect();
+            }
+            for (histories, p) in joint_next {
+                *next.entry(joint.encode(&histories)).or_insert(0.0) += p;
+            }
+        }
+        state_payoff.push(expected);
+        // sorted so the power iteration sums in the same order in every run
+        transitions.push(next.into_iter().sorted_by_key(|&(state, _)| state).collect());
+    }
+
+    // lazy power iteration, pi <- (pi + pi P) / 2 has the same fixed point and never oscillates
+    let mut distribution = vec![1.0 / joint.n_states as f64; joint.n_states];
+    for _ in 0..MAX_ITERATIONS {
+        let mut stepped : Vec<f64> = distribution.iter().map(|p| p / 2.0).collect();
+        for (state, outgoing) in transitions.iter().enumerate() {
+            for &(next, p) in outgoing {
+                stepped[next] += distribution[state] * p / 2.0;
+            }
+        }
+        let change : f64 = stepped.iter().zip(distribution.iter()).map(|(a, b)| (a - b).abs()).sum();
+        distribution = stepped;
+        if change < STATIONARY_TOLERANCE {
+            break;
+        }
+    }
+
+    let mut mean = vec![0.0; n_players];
+    for (p, payoff) in distribution.iter().zip(state_payoff.iter()) {
+        for (sum, value) in mean.iter_mut().zip(payoff.iter()) {
+            *sum += p * value;
+        }
+    }
+    Ok(mean)
+}
+
+/// the noiseless infinite game, the long run payoff of each starting state is the mean over the cycle it ends in
+fn deterministic_stationary_payoffs(board : &GameBoard, players : &[&Agent]) -> Result<Vec<f64>, SimError> {
+    let n_players = players.len();
+    let joint = JointState::new(players, board.n_actions as usize)?;
+
+    let mut next_state = vec![0usize; joint.n_states];
+    let mut state_payoff : Vec<Vec<f64>> = Vec::with_capacity(joint.n_states);
+    let genotypes : Vec<&Genotype> = players.iter().map(|agent| &agent.genotype).collect();
+    let mut perceived : Vec<Action> = Vec::with_capacity(n_players);
+    for (state, next) in next_state.iter_mut().enumerate() {
+        let mut histories = joint.decode(state);
+        let actions : Vec<Action> = genotypes.iter().zip(histories.iter())
+            .map(|(genotype, &history)| genotype.action(history))
+            .collect();
+        state_payoff.push(board.get_payoff(&actions)?.to_vec());
+        for (i, (genotype, history)) in genotypes.iter().zip(histories.iter_mut()).enumerate() {
+            perceived.clear();
+            perceived.push(actions[i]);
+            perceived.extend(actions.iter().enumerate().filter(|&(j, _)| j != i).map(|(_, &a)| a));
+            *history = genotype.push_history(*history, &perceived);
+        }
+        *next = joint.encode(&histories);
+    }
+
+    // long run payoff of every state, filled in by walking each path until it meets a known state or a cycle
+    let mut long_run : Vec<Option<Vec<f64>>> = vec![None; joint.n_states];
+    for start in 0..joint.n_states {
+        let mut path : Vec<usize> = Vec::new();
+        let mut position : HashMap<usize, usize> = HashMap::new();
+        let mut state = start;
+        while long_run[state].is_none() && !position.contains_key(&state) {
+            position.insert(state, path.len());
+            path.push(state);
+            state = next_state[state];
+        }
+        let value = match &long_run[state] {
+            Some(value) => value.clone(),
+            None => {
+                let cycle = &path[position[&state]..];
+                let mut mean = vec![0.0; n_players];
+                for &member in cycle {
+                    for (sum, value) in mean.iter_mut().zip(state_payoff[member].iter()) {
+                        *sum += value / cycle.len() as f64;
+                    }
+                }
+                mean
+            },
+        };
+        for &visited in &path {
+            long_run[visited] = Some(value.clone());
+        }
+    }
+
+    let mut mean = vec![0.0; n_players];
+    for value in long_run.iter().flatten() {
+        for (sum, v) in mean.iter_mut().zip(value.iter()) {
+            *sum += v / joint.n_states as f64;
+        }
+    }
+    Ok(mean)
+}
@@ -1,5 +1,17 @@
 pub mod agent;
+pub mod genotype;
+pub mod packed;
+pub mod registry;
 pub mod types;
+pub mod error;
+pub mod games;
 pub mod game;
+pub mod checkpoint;
+pub mod genealogy;
+pub mod strategy;
+pub mod sink;
+pub mod iterated;
 pub mod utils;
+pub mod rng;
+pub mod selection;
 pub mod test;
\ No newline at end of file
@@ -0,0 +1,127 @@
+use serde::{Serialize, Serializer};
+use crate::simulation::types::{action_to_digit, Action};
+
+const WORD_BITS : usize = u64::BITS as usize;
+
+/// a genome stored as packed u64 words, every gene takes 1, 2, 4 or 8 bits so it never straddles two words.
+/// A two action genome of memory 10 takes 16 words instead of 1024 bytes.
+/// The bits past the last gene are always 0, so two genomes are equal when their words are.
+/// # Variables:
+///     words: Vec<u64> - the genes, gene i starts at bit i * bits, the lowest bits first
+///     len: usize - the number of genes
+///     bits: u32 - the bits per gene, see PackedGenome::bits_per_gene
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct PackedGenome {
+    words : Vec<u64>,
+    len : usize,
+    bits : u32,
+}
+
+// written as the list of actions, the format of the genomes before they were packed
+impl Serialize for PackedGenome {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl PackedGenome {
+    /// the smallest power of two number of bits that holds an action of an alphabet of n_actions
+    pub fn bits_per_gene(n_actions : usize) -> u32 {
+        let needed = usize::BITS - (n_actions.max(2) - 1).leading_zeros();
+        needed.next_power_of_two()
+    }
+
+    /// packs the actions, they must be below n_actions
+    pub fn from_actions(actions : &[Action], n_actions : usize) -> PackedGenome {
+        let bits = PackedGenome::bits_per_gene(n_actions);
+        let mut genome = PackedGenome {
+            words : vec![0; (actions.len() * bits as usize).div_ceil(WORD_BITS)],
+            len : actions.len(),
+            bits,
+        };
+        for (index, &action) in actions.iter().enumerate() {
+            genome.set(index, action);
+        }
+        genome
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn mask(&self) -> u64 {
+        (1 << self.bits) - 1
+    }
+
+    /// the action of gene index, one shift and one mask
+    #[inline]
+    pub fn get(&self, index : usize) -> Action {
+        let bit = index * self.bits as usize;
+        ((self.words[bit / WORD_BITS] >> (bit % WORD_BITS)) & self.mask()) as Action
+    }
+
+    pub fn set(&mut self, index : usize, action : Action) {
+        let bit = index * self.bits as usize;
+        let (shift, mask) = (bit % WORD_BITS, self.mask());
+        let word = &mut self.words[bit / WORD_BITS];
+        *word = (*word & !(mask << shift)) | ((action as u64 & mask) << shift);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Action> + '_ {
+        (0..self.len).map(|index| self.get(index))
+    }
+
+    pub fn to_actions(&self) -> Vec<Action> {
+        self.iter().collect()
+    }
+
+    /// the genes start..start + len as a new genome
+    pub fn slice(&self, start : usize, len : usize) -> PackedGenome {
+        let mut genome = PackedGenome {
+            words : vec![0; (len * self.bits as usize).div_ceil(WORD_BITS)],
+            len,
+            bits : self.bits,
+        };
+        for index in 0..len {
+            genome.set(index, self.get(start + index));
+        }
+        genome
+    }
+
+    /// the genome repeated times times, whole words are copied when the genome fills them
+    pub fn repeat(&self, times : usize) -> PackedGenome {
+        let genome_bits = self.len * self.bits as usize;
+        if genome_bits.is_multiple_of(WORD_BITS) {
+            return PackedGenome {
+                words : self.words.repeat(times),
+                len : self.len * times,
+                bits : self.bits,
+            };
+        }
+        let mut genome = PackedGenome {
+            words : vec![0; (genome_bits * times).div_ceil(WORD_BITS)],
+            len : self.len * times,
+            bits : self.bits,
+        };
+        for index in 0..genome.len {
+            genome.set(index, self.get(index % self.len));
+        }
+        genome
+    }
+
+    /// one base 36 digit per gene, see Genotype::genome_to_id
+    pub fn to_id(&self) -> String {
+        let mut id = String::with_capacity(self.len);
+        for action in self.iter() {
+            id.push(action_to_digit(action));
+        }
+        id
+    }
+}
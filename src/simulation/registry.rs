@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use crate::simulation::{
+    agent::Agent,
+    genotype::Genotype,
+    packed::PackedGenome,
+    types::{Action, AgentMetaData, AgentRoundData},
+};
+
+/// the index of a genotype in the GenotypeRegistry of a run
+pub type GenotypeHandle = u32;
+
+/// the genotypes of a run, interned once and referred to by their handle afterwards.
+/// A genome keeps the lineage it was first registered with, like the Genealogy does, and the history its
+/// games start from: the history of the first agent of the initial population or the one of its parent.
+/// The extinct genotypes are pruned after every round so the registry does not grow with the length of the run,
+/// their handles are reused by the next new genomes.
+/// The payoffs of noiseless groups do not depend on the round, they are cached by the handles of the group.
+#[derive(Debug, Clone, Default)]
+pub struct GenotypeRegistry {
+    genotypes : Vec<Genotype>,
+    histories : Vec<Vec<Action>>,
+    handles : HashMap<PackedGenome, GenotypeHandle>,
+    free : Vec<GenotypeHandle>, // the handles of the pruned genotypes
+    group_payoffs : HashMap<Vec<GenotypeHandle>, Vec<f64>>,
+}
+
+impl GenotypeRegistry {
+    pub fn new() -> GenotypeRegistry {
+        GenotypeRegistry::default()
+    }
+
+    /// the handle of the genome of genotype, the genotype is stored with an empty history if its genome is new
+    pub fn intern(&mut self, genotype : &Genotype) -> GenotypeHandle {
+        self.intern_with_history(genotype, &[])
+    }
+
+    /// the handle of the genome of genotype, if the genome is new the genotype is stored with the last
+    /// actions of history that fit in its memory, a known genome keeps its history
+    pub fn intern_with_history(&mut self, genotype : &Genotype, history : &[Action]) -> GenotypeHandle {
+        if let Some(&handle) = self.handles.get(&genotype.genome) {
+            return handle;
+        }
+        let history = history[history.len().saturating_sub(genotype.memory_len)..].to_vec();
+        let handle = match self.free.pop() {
+            Some(handle) => {
+                self.genotypes[handle as usize] = genotype.clone();
+                self.histories[handle as usize] = history;
+                handle
+            },
+            None => {
+                self.genotypes.push(genotype.clone());
+                self.histories.push(history);
+                (self.genotypes.len() - 1) as GenotypeHandle
+            },
+        };
+        self.handles.insert(genotype.genome.clone(), handle);
+        handle
+    }
+
+    /// the handle of the genotype of agent, a new genome starts its games from the history of the agent
+    pub fn intern_agent(&mut self, agent : &Agent) -> GenotypeHandle {
+        self.intern_with_history(&agent.genotype, &agent.history())
+    }
+
+    pub fn get(&self, handle : GenotypeHandle) -> &Genotype {
+        &self.genotypes[handle as usize]
+    }
+
+    /// the history the games of the genotype start from, oldest action first
+    pub fn history(&self, handle : GenotypeHandle) -> &[Action] {
+        &self.histories[handle as usize]
+    }
+
+    /// a player of the genotype with the history its games start from
+    pub fn agent(&self, handle : GenotypeHandle) -> Agent {
+        let mut agent = Agent::from_genotype(self.get(handle).clone());
+        agent.set_history(self.history(handle));
+        agent
+    }
+
+    pub fn handle(&self, genotype : &Genotype) -> Option<GenotypeHandle> {
+        self.handles.get(&genotype.genome).copied()
+    }
+
+    /// the handles of the registered genotypes in increasing order
+    pub fn registered(&self) -> Vec<GenotypeHandle> {
+        let mut handles : Vec<GenotypeHandle> = self.handles.values().copied().collect();
+        handles.sort_unstable();
+        handles
+    }
+
+    /// the number of registered genotypes
+    pub fn len(&self) -> usize {
+        self.handles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+
+    /// one more than the largest handle, the length of the arrays indexed by handle
+    pub fn capacity(&self) -> usize {
+        self.genotypes.len()
+    }
+
+    /// removes the genotypes that are not alive unless keep is true for them, and the cached payoffs of the groups
+    /// with a genotype that is not alive. The handles are freed in increasing order so the run stays reproducible
+    /// # Args:
+    ///     alive: &[bool] - indexed by handle, true for the genotypes of the population, see capacity
+    ///     keep: F - true for a genotype that is kept while it is extinct, the genealogy uses it to keep lineages
+    pub fn prune<F: Fn(&Genotype) -> bool>(&mut self, alive : &[bool], keep : F) {
+        self.group_payoffs.retain(|group, _| group.iter().all(|&handle| alive[handle as usize]));
+        for handle in self.registered() {
+            let genotype = &self.genotypes[handle as usize];
+            if alive[handle as usize] || keep(genotype) {
+                continue;
+            }
+            self.handles.remove(&genotype.genome);
+            self.histories[handle as usize] = Vec::new();
+            self.free.push(handle);
+        }
+        // the smallest free handle is reused first
+        self.free.sort_unstable_by(|a, b| b.cmp(a));
+    }
+
+    pub fn group_payoff(&self, group : &[GenotypeHandle]) -> Option<&Vec<f64>> {
+        self.group_payoffs.get(group)
+    }
+
+    pub fn cache_group_payoff(&mut self, group : Vec<GenotypeHandle>, payoff : Vec<f64>) {
+        self.group_payoffs.insert(group, payoff);
+    }
+}
+
+/// the population of a round as arrays indexed alike, sorted by genotype so every sum over them
+/// is done in the same order in every run
+/// # Variables:
+///     pub handles: Vec<GenotypeHandle> - the genotypes
+///     pub metadata: Vec<AgentMetaData> - the count and population share of every genotype
+#[derive(Debug, Clone, Default)]
+pub struct Population {
+    pub handles : Vec<GenotypeHandle>,
+    pub metadata : Vec<AgentMetaData>,
+}
+
+impl Population {
+    /// sorts the entries by genotype
+    pub fn from_entries(registry : &GenotypeRegistry, mut entries : Vec<(GenotypeHandle, AgentMetaData)>) -> Population {
+        entries.sort_by(|a, b| registry.get(a.0).cmp(registry.get(b.0)));
+        let (handles, metadata) = entries.into_iter().unzip();
+        Population { handles, metadata }
+    }
+
+    /// interns the genotypes of a population map in genotype order, so the handles do not depend on the HashMap.
+    /// The genotypes already in the registry keep their lineage and history
+    pub fn from_map(registry : &mut GenotypeRegistry, agents_map : HashMap<Genotype, AgentMetaData>) -> Population {
+        let mut sorted : Vec<(Genotype, AgentMetaData)> = agents_map.into_iter().collect();
+        sorted.sort_by(|a, b| a.0.cmp(&b.0));
+        let (handles, metadata) = sorted.into_iter()
+            .map(|(genotype, metadata)| (registry.intern(&genotype), metadata))
+            .unzip();
+        Population { handles, metadata }
+    }
+
+    /// the population map with the genotypes cloned out of the registry
+    pub fn to_map(&self, registry : &GenotypeRegistry) -> HashMap<Genotype, AgentMetaData> {
+        self.handles.iter().zip(self.metadata.iter())
+            .map(|(&handle, metadata)| (registry.get(handle).clone(), metadata.clone()))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.handles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+}
+
+/// the result of a round for the genotypes that played, sorted by genotype like Population
+/// # Variables:
+///     pub handles: Vec<GenotypeHandle> - the genotypes
+///     pub data: Vec<AgentRoundData> - the count, score, fitness and population share of every genotype
+#[derive(Debug, Clone, Default)]
+pub struct RoundScores {
+    pub handles : Vec<GenotypeHandle>,
+    pub data : Vec<AgentRoundData>,
+}
+
+impl RoundScores {
+    pub fn len(&self) -> usize {
+        self.handles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+}
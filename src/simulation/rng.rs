@@ -0,0 +1,43 @@
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+
+/// every random draw in a run comes from a stream derived from the master seed,
+/// the round number, what the numbers are used for and a chunk index.
+/// Chunks have a fixed size so the streams do not depend on how rayon splits the work
+/// and the same seed gives the same run whatever the thread count.
+pub const CHUNK_SIZE : usize = 64;
+
+/// what a stream is used for, so that two different steps of the same round never share numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamKind {
+    Init = 0,
+    Sampling = 1,
+    Mutation = 2,
+    Play = 3,
+}
+
+/// splitmix64, used to spread the master seed and round number over the chacha key
+fn splitmix64(state : &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// the rng for one chunk of one step of one round
+/// # Args:
+///     seed: u64 - the master seed of the game
+///     round_number: u32 - the round the numbers are drawn in
+///     kind: StreamKind - the step of the round
+///     chunk: u64 - the index of the chunk of agents
+pub fn stream(seed : u64, round_number : u32, kind : StreamKind, chunk : u64) -> ChaCha8Rng {
+    let mut state = seed ^ ((round_number as u64) << 8 | kind as u64).wrapping_mul(0xD6E8_FEB8_6659_FD93);
+    let mut key = [0u8; 32];
+    for word in key.chunks_mut(8) {
+        word.copy_from_slice(&splitmix64(&mut state).to_le_bytes());
+    }
+    let mut rng = ChaCha8Rng::from_seed(key);
+    rng.set_stream(chunk);
+    rng
+}
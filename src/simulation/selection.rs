@@ -0,0 +1,253 @@
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::Rng;
+use rand_chacha::ChaCha8Rng;
+use rayon::prelude::*;
+use serde::{Serialize, Deserialize};
+use crate::simulation::{
+    error::SimError,
+    game::Game,
+    genotype::Genotype,
+    registry::{GenotypeHandle, RoundScores},
+    rng::{self, StreamKind, CHUNK_SIZE},
+};
+
+/// a selection rule of the stochastic dynamics, it picks the parents of the next round from the scores of a round.
+/// The mutations are applied by the Game afterwards.
+pub trait SelectionDynamics {
+    /// the genotype of every agent of the next round before mutation
+    /// # Args:
+    ///     game: &Game - the seed, the parameters and the registry of the run
+    ///     scores: &RoundScores - the count, score and fitness of every genotype of the round, sorted by genotype
+    ///     round_number: u32 - the round that was just played, the draws come from its Sampling streams
+    ///     n_agents: u32 - the population size
+    /// # Returns:
+    ///     Result<Vec<GenotypeHandle>, SimError> - the genotypes of the agents in a fixed order
+    fn select(
+        &self, game : &Game, scores : &RoundScores, round_number : u32, n_agents : u32
+    ) -> Result<Vec<GenotypeHandle>, SimError>;
+}
+
+/// every agent of the next round is drawn with the Game::compute_next_probability weights
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Proportional;
+
+/// n_agents Moran birth death steps per round: a parent is chosen with fitness exp(beta * score),
+/// its offspring replaces an agent chosen uniformly at random
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MoranBirthDeath {
+    pub beta : f64,
+}
+
+/// n_agents Moran death birth steps per round: an agent chosen uniformly at random dies and is replaced by
+/// the offspring of one of the other agents chosen with fitness exp(beta * score)
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MoranDeathBirth {
+    pub beta : f64,
+}
+
+/// non overlapping generations, every agent of the next round is drawn with fitness exp(beta * score),
+/// beta = 0 is neutral drift
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WrightFisher {
+    pub beta : f64,
+}
+
+/// n_agents imitation steps per round: an agent chosen uniformly at random compares itself with another one and
+/// takes its genotype with probability 1 / (1 + exp(-beta * (score_model - score_focal)))
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Fermi {
+    pub beta : f64,
+}
+
+/// the selection rule of a Game, the one that is serialized with the run
+/// # Variants:
+///     Proportional - the compute_next_probability weights, the rule of older runs
+///     MoranBirthDeath - see MoranBirthDeath
+///     MoranDeathBirth - see MoranDeathBirth
+///     WrightFisher - see WrightFisher
+///     Fermi - pairwise comparison, see Fermi
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Selection {
+    Proportional(Proportional),
+    MoranBirthDeath(MoranBirthDeath),
+    MoranDeathBirth(MoranDeathBirth),
+    WrightFisher(WrightFisher),
+    Fermi(Fermi),
+}
+
+impl Default for Selection {
+    fn default() -> Self {
+        Selection::Proportional(Proportional)
+    }
+}
+
+impl Selection {
+    /// the rule as a trait object
+    pub fn dynamics(&self) -> &dyn SelectionDynamics {
+        match self {
+            Selection::Proportional(selection) => selection,
+            Selection::MoranBirthDeath(selection) => selection,
+            Selection::MoranDeathBirth(selection) => selection,
+            Selection::WrightFisher(selection) => selection,
+            Selection::Fermi(selection) => selection,
+        }
+    }
+}
+
+impl SelectionDynamics for Selection {
+    fn select(
+        &self, game : &Game, scores : &RoundScores, round_number : u32, n_agents : u32
+    ) -> Result<Vec<GenotypeHandle>, SimError> {
+        self.dynamics().select(game, scores, round_number, n_agents)
+    }
+}
+
+impl SelectionDynamics for Proportional {
+    fn select(
+        &self, game : &Game, scores : &RoundScores, round_number : u32, n_agents : u32
+    ) -> Result<Vec<GenotypeHandle>, SimError> {
+        // the scores are sorted by genotype so the sampled indices mean the same thing in every run
+        let genotypes : Vec<&Genotype> = scores.handles.iter().map(|&handle| game.registry.get(handle)).collect();
+        let probabilities = game.next_probabilities(&genotypes, &scores.data)?;
+        if game.debug {
+            println!("Probabilities: {:?}", probabilities);
+        }
+        sample_independently(game, scores, &probabilities, round_number, n_agents)
+    }
+}
+
+impl SelectionDynamics for WrightFisher {
+    fn select(
+        &self, game : &Game, scores : &RoundScores, round_number : u32, n_agents : u32
+    ) -> Result<Vec<GenotypeHandle>, SimError> {
+        let fitness = exponential_fitness(scores, self.beta)?;
+        let weights : Vec<f64> = scores.data.iter().zip(fitness.iter())
+            .map(|(data, fitness)| data.count as f64 * fitness)
+            .collect();
+        sample_independently(game, scores, &weights, round_number, n_agents)
+    }
+}
+
+impl SelectionDynamics for MoranBirthDeath {
+    fn select(
+        &self, game : &Game, scores : &RoundScores, round_number : u32, n_agents : u32
+    ) -> Result<Vec<GenotypeHandle>, SimError> {
+        let fitness = exponential_fitness(scores, self.beta)?;
+        let mut counts = counts(scores, round_number)?;
+        let mut rng = sequential_stream(game, round_number);
+        for _ in 0..n_agents {
+            let weights : Vec<f64> = counts.iter().zip(fitness.iter()).map(|(&count, fitness)| count as f64 * fitness).collect();
+            let parent = weighted_choice(&weights, &mut rng)?;
+            let dead = weighted_choice(&counts.iter().map(|&count| count as f64).collect::<Vec<f64>>(), &mut rng)?;
+            counts[parent] += 1;
+            counts[dead] -= 1;
+        }
+        Ok(expand(scores, &counts))
+    }
+}
+
+impl SelectionDynamics for MoranDeathBirth {
+    fn select(
+        &self, game : &Game, scores : &RoundScores, round_number : u32, n_agents : u32
+    ) -> Result<Vec<GenotypeHandle>, SimError> {
+        let fitness = exponential_fitness(scores, self.beta)?;
+        let mut counts = counts(scores, round_number)?;
+        let mut rng = sequential_stream(game, round_number);
+        for _ in 0..n_agents {
+            let dead = weighted_choice(&counts.iter().map(|&count| count as f64).collect::<Vec<f64>>(), &mut rng)?;
+            counts[dead] -= 1;
+            // a population of one agent has nobody to replace it, the agent stays
+            if counts.iter().all(|&count| count == 0) {
+                counts[dead] += 1;
+                continue;
+            }
+            let weights : Vec<f64> = counts.iter().zip(fitness.iter()).map(|(&count, fitness)| count as f64 * fitness).collect();
+            let parent = weighted_choice(&weights, &mut rng)?;
+            counts[parent] += 1;
+        }
+        Ok(expand(scores, &counts))
+    }
+}
+
+impl SelectionDynamics for Fermi {
+    fn select(
+        &self, game : &Game, scores : &RoundScores, round_number : u32, n_agents : u32
+    ) -> Result<Vec<GenotypeHandle>, SimError> {
+        if !self.beta.is_finite() {
+            return Err(SimError::InvalidParameter(format!("Invalid selection strength: {}", self.beta)));
+        }
+        let mut counts = counts(scores, round_number)?;
+        let mut rng = sequential_stream(game, round_number);
+        for _ in 0..n_agents {
+            let focal = weighted_choice(&counts.iter().map(|&count| count as f64).collect::<Vec<f64>>(), &mut rng)?;
+            // the model is one of the other agents
+            let others : Vec<f64> = counts.iter().enumerate()
+                .map(|(index, &count)| if index == focal { count as f64 - 1.0 } else { count as f64 })
+                .collect();
+            if others.iter().all(|&count| count <= 0.0) {
+                continue;
+            }
+            let model = weighted_choice(&others, &mut rng)?;
+            let difference = scores.data[model].score - scores.data[focal].score;
+            if rng.gen::<f64>() < 1.0 / (1.0 + (-self.beta * difference).exp()) {
+                counts[focal] -= 1;
+                counts[model] += 1;
+            }
+        }
+        Ok(expand(scores, &counts))
+    }
+}
+
+/// exp(beta * score) of every genotype, the scores are shifted by the largest one so it can not overflow
+fn exponential_fitness(scores : &RoundScores, beta : f64) -> Result<Vec<f64>, SimError> {
+    if !beta.is_finite() || beta < 0.0 {
+        return Err(SimError::InvalidParameter(format!("Invalid selection strength: {} expected a value >= 0", beta)));
+    }
+    let best = scores.data.iter().map(|data| data.score).fold(f64::NEG_INFINITY, f64::max);
+    Ok(scores.data.iter().map(|data| (beta * (data.score - best)).exp()).collect())
+}
+
+/// the number of agents of every genotype of the round
+fn counts(scores : &RoundScores, round_number : u32) -> Result<Vec<u32>, SimError> {
+    let counts : Vec<u32> = scores.data.iter().map(|data| data.count).collect();
+    if counts.iter().all(|&count| count == 0) {
+        return Err(SimError::ExtinctPopulation { round_number });
+    }
+    Ok(counts)
+}
+
+/// the agents of the counts in genotype order
+fn expand(scores : &RoundScores, counts : &[u32]) -> Vec<GenotypeHandle> {
+    scores.handles.iter().zip(counts.iter())
+        .flat_map(|(&handle, &count)| std::iter::repeat_n(handle, count as usize))
+        .collect()
+}
+
+/// the steps of a Moran or imitation round depend on each other, they draw from a single stream of the round
+fn sequential_stream(game : &Game, round_number : u32) -> ChaCha8Rng {
+    rng::stream(game.seed, round_number, StreamKind::Sampling, 0)
+}
+
+fn weighted_choice(weights : &[f64], rng : &mut ChaCha8Rng) -> Result<usize, SimError> {
+    let dist = WeightedIndex::new(weights)
+        .map_err(|e| SimError::InvalidProbability(format!("Can not sample the next population: {}", e)))?;
+    Ok(dist.sample(rng))
+}
+
+/// draws n_agents genotypes independently with the given weights, in parallel with one stream per chunk of agents
+fn sample_independently(
+    game : &Game, scores : &RoundScores, weights : &[f64], round_number : u32, n_agents : u32
+) -> Result<Vec<GenotypeHandle>, SimError> {
+    let dist = WeightedIndex::new(weights)
+        .map_err(|e| SimError::InvalidProbability(format!("Can not sample the next population: {}", e)))?;
+    Ok((0..n_agents as usize).collect::<Vec<usize>>()
+        .par_chunks(CHUNK_SIZE)
+        .enumerate()
+        .flat_map_iter(|(chunk, indices)| {
+            let mut rng = rng::stream(game.seed, round_number, StreamKind::Sampling, chunk as u64);
+            indices.iter()
+                .map(|_| scores.handles[dist.sample(&mut rng)])
+                .collect::<Vec<GenotypeHandle>>()
+        })
+        .collect())
+}
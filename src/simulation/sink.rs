@@ -0,0 +1,424 @@
+use polars::prelude::*;
+use polars_io::{csv, ipc, parquet};
+use itertools::Itertools;
+use serde_json::{Map, Value};
+use std::{fmt, fs::File, io::{BufWriter, Write}, sync::{Arc, Mutex}};
+use crate::simulation::{error::SimError, types::RoundState};
+
+/// the rows a sink builds from every round, implemented by the agent table and the aggregate table
+pub trait RoundRows: Send {
+    fn push_round(&mut self, round_state : &RoundState, seed : u64);
+
+    fn is_empty(&self) -> bool;
+
+    fn clear(&mut self);
+
+    fn to_dataframe(&self) -> Result<DataFrame, SimError>;
+}
+
+/// the tables a sink can write
+/// # Variants:
+///     Agents - one row per genotype and round, see RoundColumns
+///     Aggregates - one row per round with population wide values, see AggregateColumns
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RoundTable {
+    #[default]
+    Agents,
+    Aggregates,
+}
+
+impl RoundTable {
+    fn buffer(&self) -> Box<dyn RoundRows> {
+        match self {
+            RoundTable::Agents => Box::new(RoundColumns::new()),
+            RoundTable::Aggregates => Box::new(AggregateColumns::new()),
+        }
+    }
+
+    fn schema(&self) -> Result<Schema, SimError> {
+        Ok(self.buffer().to_dataframe()?.schema())
+    }
+}
+
+/// the columns of the round dataframe, filled round by round and turned into a dataframe in one go
+/// instead of stacking one row at a time
+/// # Variables:
+///     genotype_id - Genotype::genotype_id, the same for a genome in every run
+///     parent_genotype_id - the genotype_id of the genotype it mutated from, null for the initial population
+///     birth_round - the round in which the genotype first appeared
+///     strategy - the name of the strategy of the genome, null when it is not a known strategy
+#[derive(Debug, Clone, Default)]
+pub struct RoundColumns {
+    round_number : Vec<u32>,
+    agent_id : Vec<String>,
+    genotype_id : Vec<u64>,
+    parent_genotype_id : Vec<Option<u64>>,
+    birth_round : Vec<u32>,
+    memory_len : Vec<u32>,
+    genome_len : Vec<u32>,
+    strategy : Vec<Option<&'static str>>,
+    count : Vec<u32>,
+    score : Vec<f64>,
+    fitness : Vec<f64>,
+    population_share : Vec<f64>,
+    seed : Vec<u64>,
+}
+
+impl RoundColumns {
+    pub fn new() -> RoundColumns {
+        RoundColumns::default()
+    }
+}
+
+impl RoundRows for RoundColumns {
+    /// adds one row per genotype of the round, the genotypes are sorted so the rows have the same order in every run
+    fn push_round(&mut self, round_state : &RoundState, seed : u64) {
+        for (genotype, data) in round_state.agent_data.iter().sorted_by(|a, b| a.0.cmp(b.0)) {
+            self.round_number.push(round_state.round_number);
+            self.agent_id.push(genotype.id.clone());
+            self.genotype_id.push(genotype.genotype_id());
+            self.parent_genotype_id.push(genotype.parent_id);
+            self.birth_round.push(genotype.birth_round);
+            self.memory_len.push(genotype.memory_len as u32);
+            self.genome_len.push(genotype.genome.len() as u32);
+            self.strategy.push(genotype.strategy(round_state.n_players).map(|strategy| strategy.name()));
+            self.count.push(data.count);
+            self.score.push(data.score);
+            self.fitness.push(data.fitness);
+            self.population_share.push(data.population_share);
+            self.seed.push(seed);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.round_number.is_empty()
+    }
+
+    fn clear(&mut self) {
+        *self = RoundColumns::default();
+    }
+
+    fn to_dataframe(&self) -> Result<DataFrame, SimError> {
+        Ok(DataFrame::new(vec![
+            Series::new("round_number", &self.round_number),
+            Series::new("agent_id", &self.agent_id),
+            Series::new("genotype_id", &self.genotype_id),
+            Series::new("parent_genotype_id", &self.parent_genotype_id),
+            Series::new("birth_round", &self.birth_round),
+            Series::new("memory_len", &self.memory_len),
+            Series::new("genome_len", &self.genome_len),
+            Series::new("strategy", &self.strategy),
+            Series::new("count", &self.count),
+            Series::new("score", &self.score),
+            Series::new("fitness", &self.fitness),
+            Series::new("population_share", &self.population_share),
+            Series::new("seed", &self.seed),
+        ])?)
+    }
+}
+
+/// one row per round with values over the whole population,
+/// the means are weighted by the population share of every genotype and the diversity columns
+/// are the fields of DiversityMetrics
+#[derive(Debug, Clone, Default)]
+pub struct AggregateColumns {
+    round_number : Vec<u32>,
+    n_genotypes : Vec<u32>,
+    mean_score : Vec<f64>,
+    mean_memory_len : Vec<f64>,
+    shannon_entropy : Vec<f64>,
+    simpson_diversity : Vec<f64>,
+    effective_number : Vec<f64>,
+    dominant_share : Vec<f64>,
+    seed : Vec<u64>,
+}
+
+impl AggregateColumns {
+    pub fn new() -> AggregateColumns {
+        AggregateColumns::default()
+    }
+}
+
+impl RoundRows for AggregateColumns {
+    fn push_round(&mut self, round_state : &RoundState, seed : u64) {
+        let sorted_data = round_state.agent_data.iter().sorted_by(|a, b| a.0.cmp(b.0));
+        let (mut mean_score, mut mean_memory_len) = (0.0, 0.0);
+        for (genotype, data) in sorted_data {
+            mean_score += data.population_share * data.score;
+            mean_memory_len += data.population_share * genotype.memory_len as f64;
+        }
+        self.round_number.push(round_state.round_number);
+        self.n_genotypes.push(round_state.agent_data.len() as u32);
+        self.mean_score.push(mean_score);
+        self.mean_memory_len.push(mean_memory_len);
+        self.shannon_entropy.push(round_state.metrics.shannon_entropy);
+        self.simpson_diversity.push(round_state.metrics.simpson_diversity);
+        self.effective_number.push(round_state.metrics.effective_number);
+        self.dominant_share.push(round_state.metrics.dominant_share);
+        self.seed.push(seed);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.round_number.is_empty()
+    }
+
+    fn clear(&mut self) {
+        *self = AggregateColumns::default();
+    }
+
+    fn to_dataframe(&self) -> Result<DataFrame, SimError> {
+        Ok(DataFrame::new(vec![
+            Series::new("round_number", &self.round_number),
+            Series::new("n_genotypes", &self.n_genotypes),
+            Series::new("mean_score", &self.mean_score),
+            Series::new("mean_memory_len", &self.mean_memory_len),
+            Series::new("shannon_entropy", &self.shannon_entropy),
+            Series::new("simpson_diversity", &self.simpson_diversity),
+            Series::new("effective_number", &self.effective_number),
+            Series::new("dominant_share", &self.dominant_share),
+            Series::new("seed", &self.seed),
+        ])?)
+    }
+}
+
+/// receives the rounds of a game while it runs, see Game::add_sink
+pub trait RoundSink: Send {
+    /// seed is the master seed of the game that played the round
+    fn write_round(&mut self, round_state : &RoundState, seed : u64) -> Result<(), SimError>;
+
+    /// called at the end of Game::run, also when a round failed, writes what is still buffered and closes the output
+    fn finish(&mut self) -> Result<(), SimError>;
+
+    /// a short description of the output used by Debug, usually the file path
+    fn describe(&self) -> String;
+}
+
+/// a file format that is written one dataframe at a time
+pub trait FrameWriter: Send {
+    fn write_frame(&mut self, df : &DataFrame) -> Result<(), SimError>;
+
+    fn finish(&mut self) -> Result<(), SimError>;
+}
+
+impl FrameWriter for parquet::BatchedWriter<File> {
+    fn write_frame(&mut self, df : &DataFrame) -> Result<(), SimError> {
+        Ok(self.write_batch(df)?)
+    }
+
+    fn finish(&mut self) -> Result<(), SimError> {
+        parquet::BatchedWriter::finish(self)?;
+        Ok(())
+    }
+}
+
+impl FrameWriter for ipc::BatchedWriter<File> {
+    fn write_frame(&mut self, df : &DataFrame) -> Result<(), SimError> {
+        Ok(self.write_batch(df)?)
+    }
+
+    fn finish(&mut self) -> Result<(), SimError> {
+        Ok(ipc::BatchedWriter::finish(self)?)
+    }
+}
+
+impl FrameWriter for csv::BatchedWriter<File> {
+    fn write_frame(&mut self, df : &DataFrame) -> Result<(), SimError> {
+        Ok(self.write_batch(df)?)
+    }
+
+    fn finish(&mut self) -> Result<(), SimError> {
+        Ok(csv::BatchedWriter::finish(self)?)
+    }
+}
+
+/// writes every row as a json object on its own line, the keys are the column names
+pub struct JsonLinesWriter {
+    writer : BufWriter<File>,
+}
+
+impl FrameWriter for JsonLinesWriter {
+    fn write_frame(&mut self, df : &DataFrame) -> Result<(), SimError> {
+        let columns = df.get_columns();
+        for row in 0..df.height() {
+            let mut object = Map::new();
+            for column in columns {
+                object.insert(column.name().to_string(), any_value_to_json(column.get(row)?));
+            }
+            serde_json::to_writer(&mut self.writer, &object)?;
+            self.writer.write_all(b"\n")?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), SimError> {
+        Ok(self.writer.flush()?)
+    }
+}
+
+fn any_value_to_json(value : AnyValue) -> Value {
+    match value {
+        AnyValue::Null => Value::Null,
+        AnyValue::Boolean(b) => Value::from(b),
+        AnyValue::String(s) => Value::from(s),
+        AnyValue::StringOwned(s) => Value::from(s.as_str()),
+        AnyValue::UInt8(n) => Value::from(n),
+        AnyValue::UInt16(n) => Value::from(n),
+        AnyValue::UInt32(n) => Value::from(n),
+        AnyValue::UInt64(n) => Value::from(n),
+        AnyValue::Int8(n) => Value::from(n),
+        AnyValue::Int16(n) => Value::from(n),
+        AnyValue::Int32(n) => Value::from(n),
+        AnyValue::Int64(n) => Value::from(n),
+        AnyValue::Float32(x) => Value::from(x),
+        AnyValue::Float64(x) => Value::from(x),
+        other => Value::from(other.to_string()),
+    }
+}
+
+/// buffers the rows of the rounds and hands them to a FrameWriter every flush_every rounds
+/// # Variables:
+///     file_path: String - the output file
+///     buffer: Box<dyn RoundRows> - the rows of the table the sink writes
+///     flush_every: u32 - the number of rounds written at once, one row group for parquet and one record batch for ipc
+pub struct FrameSink<W: FrameWriter> {
+    file_path : String,
+    writer : Option<W>,
+    buffer : Box<dyn RoundRows>,
+    buffered_rounds : u32,
+    flush_every : u32,
+}
+
+/// apache parquet, the file can only be read once the run is finished
+pub type ParquetSink = FrameSink<parquet::BatchedWriter<File>>;
+/// arrow ipc (feather v2), the file can only be read once the run is finished
+pub type IpcSink = FrameSink<ipc::BatchedWriter<File>>;
+/// csv with a header line
+pub type CsvSink = FrameSink<csv::BatchedWriter<File>>;
+/// newline delimited json, one object per row
+pub type JsonLinesSink = FrameSink<JsonLinesWriter>;
+
+// new writes the agent table and with_table either table
+impl ParquetSink {
+    pub fn new(file_path : &str, flush_every : u32) -> Result<ParquetSink, SimError> {
+        ParquetSink::with_table(file_path, RoundTable::Agents, flush_every)
+    }
+
+    pub fn with_table(file_path : &str, table : RoundTable, flush_every : u32) -> Result<ParquetSink, SimError> {
+        let writer = ParquetWriter::new(File::create(file_path)?).batched(&table.schema()?)?;
+        Ok(FrameSink::from_writer(file_path, writer, table, flush_every))
+    }
+}
+
+impl IpcSink {
+    pub fn new(file_path : &str, flush_every : u32) -> Result<IpcSink, SimError> {
+        IpcSink::with_table(file_path, RoundTable::Agents, flush_every)
+    }
+
+    pub fn with_table(file_path : &str, table : RoundTable, flush_every : u32) -> Result<IpcSink, SimError> {
+        let writer = ipc::IpcWriter::new(File::create(file_path)?).batched(&table.schema()?)?;
+        Ok(FrameSink::from_writer(file_path, writer, table, flush_every))
+    }
+}
+
+impl CsvSink {
+    pub fn new(file_path : &str, flush_every : u32) -> Result<CsvSink, SimError> {
+        CsvSink::with_table(file_path, RoundTable::Agents, flush_every)
+    }
+
+    pub fn with_table(file_path : &str, table : RoundTable, flush_every : u32) -> Result<CsvSink, SimError> {
+        let writer = csv::CsvWriter::new(File::create(file_path)?).batched(&table.schema()?)?;
+        Ok(FrameSink::from_writer(file_path, writer, table, flush_every))
+    }
+}
+
+impl JsonLinesSink {
+    pub fn new(file_path : &str, flush_every : u32) -> Result<JsonLinesSink, SimError> {
+        JsonLinesSink::with_table(file_path, RoundTable::Agents, flush_every)
+    }
+
+    pub fn with_table(file_path : &str, table : RoundTable, flush_every : u32) -> Result<JsonLinesSink, SimError> {
+        let writer = JsonLinesWriter { writer: BufWriter::new(File::create(file_path)?) };
+        Ok(FrameSink::from_writer(file_path, writer, table, flush_every))
+    }
+}
+
+impl<W: FrameWriter> FrameSink<W> {
+    pub fn from_writer(file_path : &str, writer : W, table : RoundTable, flush_every : u32) -> FrameSink<W> {
+        FrameSink {
+            file_path : file_path.to_string(),
+            writer : Some(writer),
+            buffer : table.buffer(),
+            buffered_rounds : 0,
+            flush_every : flush_every.max(1),
+        }
+    }
+
+    /// writes the buffered rounds
+    pub fn flush(&mut self) -> Result<(), SimError> {
+        let writer = self.writer.as_mut().ok_or_else(|| SimError::Io(std::io::Error::other(
+            format!("Sink {} is already finished", self.file_path)
+        )))?;
+        if !self.buffer.is_empty() {
+            writer.write_frame(&self.buffer.to_dataframe()?)?;
+        }
+        self.buffer.clear();
+        self.buffered_rounds = 0;
+        Ok(())
+    }
+}
+
+impl<W: FrameWriter> RoundSink for FrameSink<W> {
+    fn write_round(&mut self, round_state : &RoundState, seed : u64) -> Result<(), SimError> {
+        self.buffer.push_round(round_state, seed);
+        self.buffered_rounds += 1;
+        if self.buffered_rounds >= self.flush_every {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), SimError> {
+        if self.writer.is_none() {
+            return Ok(());
+        }
+        self.flush()?;
+        if let Some(mut writer) = self.writer.take() {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        self.file_path.clone()
+    }
+}
+
+/// a shared handle on a sink so Game stays Clone and Debug, clones of a game write to the same output
+#[derive(Clone)]
+pub struct SinkHandle(Arc<Mutex<Box<dyn RoundSink>>>);
+
+impl SinkHandle {
+    pub fn new<S: RoundSink + 'static>(sink : S) -> SinkHandle {
+        SinkHandle(Arc::new(Mutex::new(Box::new(sink))))
+    }
+
+    pub fn write_round(&self, round_state : &RoundState, seed : u64) -> Result<(), SimError> {
+        self.lock().write_round(round_state, seed)
+    }
+
+    pub fn finish(&self) -> Result<(), SimError> {
+        self.lock().finish()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Box<dyn RoundSink>> {
+        // a poisoned sink only means another thread panicked while writing, the buffer is still usable
+        self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl fmt::Debug for SinkHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SinkHandle({})", self.lock().describe())
+    }
+}
@@ -0,0 +1,103 @@
+use serde::{Serialize, Deserialize};
+use std::fmt;
+use crate::simulation::types::Action;
+
+/// well known strategies of the two player, two action games.
+/// In a two player game the history holds the last moves as [own, opponent] pairs, oldest first,
+/// so memory 1 sees the last opponent move, memory 2 the last own and opponent moves
+/// and memory 3 adds the opponent move before.
+/// # Variants:
+///     AllC - always cooperates
+///     AllD - always defects
+///     TitForTat - plays the last move of the opponent
+///     AntiTitForTat - plays the opposite of the last move of the opponent
+///     WinStayLoseShift - Pavlov, cooperates after mutual cooperation or mutual defection
+///     Grim - GRIM-like, cooperates only after mutual cooperation
+///     Alternator - plays the opposite of its own last move
+///     TitForTwoTats - defects only after two defections of the opponent
+///     TwoTitsForTat - defects after any defection of the opponent in the last two rounds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Strategy {
+    AllC,
+    AllD,
+    TitForTat,
+    AntiTitForTat,
+    WinStayLoseShift,
+    Grim,
+    Alternator,
+    TitForTwoTats,
+    TwoTitsForTat,
+}
+
+/// the minimal genome of every named strategy, defection is 0 and cooperation 1
+const KNOWN_STRATEGIES : [(Strategy, &[Action]); 9] = [
+    (Strategy::AllD, &[0]),
+    (Strategy::AllC, &[1]),
+    (Strategy::TitForTat, &[0, 1]),
+    (Strategy::AntiTitForTat, &[1, 0]),
+    (Strategy::WinStayLoseShift, &[1, 0, 0, 1]),
+    (Strategy::Grim, &[0, 0, 0, 1]),
+    (Strategy::Alternator, &[1, 1, 0, 0]),
+    (Strategy::TitForTwoTats, &[0, 1, 0, 1, 1, 1, 1, 1]),
+    (Strategy::TwoTitsForTat, &[0, 0, 0, 0, 0, 1, 0, 1]),
+];
+
+impl Strategy {
+    /// the short name used in the exports
+    pub fn name(&self) -> &'static str {
+        match self {
+            Strategy::AllC => "ALLC",
+            Strategy::AllD => "ALLD",
+            Strategy::TitForTat => "TFT",
+            Strategy::AntiTitForTat => "ATFT",
+            Strategy::WinStayLoseShift => "WSLS",
+            Strategy::Grim => "GRIM",
+            Strategy::Alternator => "ALT",
+            Strategy::TitForTwoTats => "TF2T",
+            Strategy::TwoTitsForTat => "TTFT",
+        }
+    }
+
+    /// names a genome of a two player, two action game, a genome that only differs from a known strategy by
+    /// duplications (a longer memory whose oldest actions are ignored) gets the same name
+    /// # Args:
+    ///     genome: &[Action] - k^memory_len genes indexed by the history read as a base k number
+    ///     memory_len: usize - the memory length of the genome
+    ///     n_actions: usize - k, only two action genomes are named
+    ///     n_players: u32 - the group size, with more players the history no longer holds [own, opponent] pairs
+    /// # Returns:
+    ///     Option<Strategy> - None when the minimal genome is not a known strategy
+    pub fn classify(genome : &[Action], memory_len : usize, n_actions : usize, n_players : u32) -> Option<Strategy> {
+        if n_actions != 2 || n_players != 2 {
+            return None;
+        }
+        let (minimal, _) = minimal_genome(genome, memory_len, n_actions);
+        KNOWN_STRATEGIES.iter()
+            .find(|(_, known)| *known == minimal.as_slice())
+            .map(|&(strategy, _)| strategy)
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// undoes duplications, as long as the k blocks of the genome are equal the oldest action of
+/// the history is ignored and the genome is replaced by one block
+/// # Returns:
+///     (Vec<Action>, usize) - the shortest genome with the same behaviour and its memory length
+pub fn minimal_genome(genome : &[Action], memory_len : usize, n_actions : usize) -> (Vec<Action>, usize) {
+    let mut genome = genome;
+    let mut memory_len = memory_len;
+    while memory_len > 0 && n_actions > 1 {
+        let block = genome.len() / n_actions;
+        if !genome.chunks(block).all(|chunk| chunk == &genome[..block]) {
+            break;
+        }
+        genome = &genome[..block];
+        memory_len -= 1;
+    }
+    (genome.to_vec(), memory_len)
+}
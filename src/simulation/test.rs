@@ -1,9 +1,30 @@
-use crate::simulation::{types::{RoundState, AgentRoundData, GameBoard}, agent::Agent, game::Game};
-use std::collections::HashMap;
-use std::collections;
+use crate::simulation::{
+    types::{Action, GameBoard}, agent::Agent, error::SimError, checkpoint::Checkpoint, game::{Game, GroupSampling, PopulationDynamics}, genealogy::Genealogy, genotype::{Genotype, MutationKind}, iterated::{self, GameLength, Noise}, strategy::Strategy,
+    selection::{Fermi, MoranBirthDeath, MoranDeathBirth, Selection, WrightFisher},
+};
+use std::collections::{HashMap, HashSet};
 use itertools::Itertools;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 use approx::assert_abs_diff_eq;
 
+/// (round_number, id, count, score bits, fitness bits) of every genotype of every round
+type RoundSummary = Vec<(u32, String, u32, u64, u64)>;
+
+/// the rounds of the game kept in memory, sorted so runs can be compared
+fn round_summary(game : &Game) -> RoundSummary {
+    game.rounds.iter()
+        .flat_map(|round_state| round_state.agent_data.iter().map(|(agent, data)| (
+            round_state.round_number,
+            agent.id.clone(),
+            data.count,
+            data.score.to_bits(),
+            data.fitness.to_bits(),
+        )))
+        .sorted()
+        .collect()
+}
+
 #[derive(Clone, Debug)]
 struct TestData {
     pub score : f64,
@@ -22,17 +43,21 @@ pub fn run_test(n_players : u32) {
         Err(e) => panic!("Error creating game board: {}", e),
     }; 
 
-    let agent1 = Agent::random_init(2);
-    let agent2 = Agent::random_init(2);
-    let agent3 = Agent::random_init( 2);
-    let agent4 = Agent::random_init( 2);
-
-
-    let mut agents = vec![agent1, agent2, agent3];
-    let mut cloned_agents_map = Game::agents_to_hashmap(&agents);
+    // one agent per seat, a repeated genotype would leave fewer genotypes than seats
+    let mut agents : Vec<Agent> = Vec::new();
+    while agents.len() < n_players as usize {
+        let agent = Agent::random_init(2).unwrap();
+        if agents.iter().all(|other| other.genotype != agent.genotype) {
+            agents.push(agent);
+        }
+    }
+    let cloned_agents_map = Game::agents_to_hashmap(&agents);
 
-    let mut cloned_agents = agents.clone();
-    assert_eq!(agents.sort(), cloned_agents.sort());
+    let mut genotypes : Vec<Genotype> = agents.iter().map(|agent| agent.genotype.clone()).collect();
+    let mut cloned_agents = genotypes.clone();
+    genotypes.sort();
+    cloned_agents.sort();
+    assert_eq!(genotypes, cloned_agents);
 
     let mut game = match Game::new(
         game_board,
@@ -42,22 +67,24 @@ pub fn run_test(n_players : u32) {
         Ok(game) => game,
         Err(e) => panic!("Error creating game: {}", e),
     };
+    // the known outcomes below follow the scoring of groups of distinct genotypes
+    game.group_sampling = GroupSampling::Distinct;
 
 
-    let mut sorted_agents = cloned_agents.iter().collect::<Vec<_>>();
-    sorted_agents.sort();
-    let actions = sorted_agents.iter().sorted()
+    let mut sorted_agents = agents.iter().collect::<Vec<_>>();
+    sorted_agents.sort_by(|a, b| a.genotype.cmp(&b.genotype));
+    let actions = sorted_agents.iter()
         .map(
             |agent| agent.get_action()
         )
-        .collect::<Vec<bool>>();
+        .collect::<Vec<Action>>();
 
     let scores = game.game_board.get_payoff(
         &actions
-    );
+    ).unwrap();
 
     let mut weighted_scores : Vec<f64> = Vec::new();
-    for (i, agent) in cloned_agents.iter().enumerate() {
+    for i in 0..cloned_agents.len() {
         let score : Vec<f64> = scores.iter().zip(cloned_agents.iter()).enumerate().map(
             |(j , (_, agent))|
             {
@@ -73,20 +100,22 @@ pub fn run_test(n_players : u32) {
     let average_payoff = (weighted_scores.iter().zip(cloned_agents.iter())
         .map(
             |(weighted_score, agent)| 
-            weighted_score * cloned_agents_map.get(agent).unwrap().population_share as f64
+            weighted_score * cloned_agents_map.get(agent).unwrap().population_share
         ).sum::<f64>()) / n_players as f64; 
     // the average is simply the sum of the alrady weighted scores
     // we "manually" compute the average.
-    let mut known_outcomes : HashMap<Agent, TestData> = HashMap::new();
+    let mut known_outcomes : HashMap<Genotype, TestData> = HashMap::new();
     weighted_scores.iter().zip(cloned_agents.iter()).for_each(|(score, agent)| {
         known_outcomes.insert(agent.clone(), TestData{score: *score, fitness: *score - average_payoff});
     });
 
-    game.run(1, agents );
+    if let Err(e) = game.run(1, agents ) {
+        panic!("Error running game: {}", e);
+    }
     let state = game.rounds.last().unwrap();
 
     state.agent_data.iter().for_each(|(agent, agent_data)| {
-        let known_outcome = known_outcomes.get(&agent).unwrap();
+        let known_outcome = known_outcomes.get(agent).unwrap();
         assert_abs_diff_eq!(agent_data.score, known_outcome.score);
         assert_abs_diff_eq!(agent_data.fitness, known_outcome.fitness);
     });
@@ -96,3 +125,630 @@ pub fn run_test(n_players : u32) {
 
 }
 
+
+/// a genotype meets copies of itself: in a one move prisoner's dilemma (T, R, P, S) = (5, 3, 1, 0) with 3/4 ALLC and 1/4 ALLD agents
+/// ALLC scores 3/4 R + 1/4 S and ALLD 3/4 T + 1/4 P, a population of ALLC alone scores R
+pub fn self_play_test() {
+    let new_game = || {
+        let game_board = GameBoard::prisoners_dilemma(5.0, 3.0, 1.0, 0.0).unwrap();
+        Game::new(game_board, false, false).unwrap()
+    };
+    let all_c = Agent::new(vec![1], vec![], 0, 2).unwrap();
+    let all_d = Agent::new(vec![0], vec![], 0, 2).unwrap();
+
+    let mut game = new_game();
+    let agents = [vec![all_c.clone(); 30], vec![all_d.clone(); 10]].concat();
+    game.run(1, agents).unwrap();
+    let state = game.rounds.last().unwrap();
+    let mean_score = 0.75 * 2.25 + 0.25 * 4.0;
+    assert_abs_diff_eq!(state.agent_data[&all_c.genotype].score, 2.25);
+    assert_abs_diff_eq!(state.agent_data[&all_c.genotype].fitness, 2.25 - mean_score);
+    assert_abs_diff_eq!(state.agent_data[&all_d.genotype].score, 4.0);
+    assert_abs_diff_eq!(state.agent_data[&all_d.genotype].fitness, 4.0 - mean_score);
+
+    let mut game = new_game();
+    game.run(1, vec![all_c.clone(); 40]).unwrap();
+    let state = game.rounds.last().unwrap();
+    assert_abs_diff_eq!(state.agent_data[&all_c.genotype].score, 3.0);
+    assert_abs_diff_eq!(state.agent_data[&all_c.genotype].fitness, 0.0);
+}
+
+/// the N player games are generated from the number of cooperators, threshold and group size are checked
+/// instead of truncating the threshold or allocating a table of 2^n_players profiles
+pub fn group_game_test() {
+    // r = 1.5 and c = 1, two cooperators out of 3 put 2 into the pot that pays everyone 1.5 * 2 / 3
+    let public_goods = GameBoard::new("public_goods".to_string(), 3).unwrap();
+    assert_eq!(public_goods.get_payoff(&[1, 1, 0]).unwrap(), &[0.0, 0.0, 1.0]);
+    let threshold = GameBoard::from_params("threshold", 3, &[2.0, 1.0, 2.0]).unwrap();
+    assert_eq!(threshold.get_payoff(&[1, 0, 0]).unwrap(), &[-1.0, 0.0, 0.0]);
+    assert_eq!(threshold.get_payoff(&[1, 0, 1]).unwrap(), &[1.0, 2.0, 1.0]);
+
+    assert!(matches!(GameBoard::from_params("threshold", 3, &[2.0, 1.0, 2.7]), Err(SimError::InvalidParameter(_))));
+    assert!(matches!(GameBoard::new("public_goods".to_string(), 70), Err(SimError::InvalidParameter(_))));
+    assert!(matches!(GameBoard::new("volunteers_dilemma".to_string(), 40), Err(SimError::InvalidParameter(_))));
+}
+
+/// 20 ALLC and 20 ALLD agents in the asymmetric games: in matching pennies each seat wins as often as it loses
+/// whatever the strategy so both score 0, in battle of the sexes both coordinate with themselves only and score
+/// 1/2 * (preferred + other) / 2. The symmetric games are recognized as such
+pub fn asymmetric_game_test() {
+    let all_c = Agent::new(vec![1], vec![], 0, 2).unwrap();
+    let all_d = Agent::new(vec![0], vec![], 0, 2).unwrap();
+    let score = |game_board : GameBoard, genotype : &Genotype| {
+        assert!(!game_board.is_symmetric());
+        let mut game = Game::new(game_board, false, false).unwrap();
+        game.run(1, [vec![all_c.clone(); 20], vec![all_d.clone(); 20]].concat()).unwrap();
+        game.rounds.last().unwrap().agent_data[genotype].score
+    };
+
+    for genotype in [&all_c.genotype, &all_d.genotype] {
+        assert_abs_diff_eq!(score(GameBoard::new("matching_pennies".to_string(), 2).unwrap(), genotype), 0.0);
+        assert_abs_diff_eq!(score(GameBoard::new("battle_of_the_sexes".to_string(), 2).unwrap(), genotype), 0.75);
+    }
+    assert!(GameBoard::new("prisoners_dilemma".to_string(), 2).unwrap().is_symmetric());
+    assert!(GameBoard::new("prisoners_dilemma".to_string(), 3).unwrap().is_symmetric());
+    assert!(GameBoard::new("public_goods".to_string(), 4).unwrap().is_symmetric());
+}
+
+/// the iterated games of a prisoner's dilemma (T, R, P, S) = (5, 3, 1, 0) against known values:
+/// TFT that starts by cooperating against ALLD loses S once and then gets P, ALLD gets T once and then P,
+/// over 10 moves that is [0.9, 1.4] and with continuation w it is [w, (1 - w) T + w P].
+/// In the infinite game both end in mutual defection, and WSLS against itself with an execution error e
+/// visits CC, CD, DC and DD with pi_CD = pi_DC = e (1 - e) and pi_CC = (1 - e)^2 (1 - 2 pi_CD) + 2 e^2 pi_CD
+pub fn iterated_game_test() {
+    let game_board = GameBoard::prisoners_dilemma(5.0, 3.0, 1.0, 0.0).unwrap();
+    let tft = Agent::new(vec![0, 1], vec![1], 1, 2).unwrap();
+    let all_d = Agent::new(vec![0], vec![], 0, 2).unwrap();
+    let noiseless = Noise::default();
+    let mut rng = rand::thread_rng();
+    let play = |players : &[&Agent], length : GameLength, noise : Noise, rng : &mut rand::rngs::ThreadRng| {
+        iterated::play(&game_board, players, length, noise, rng)
+    };
+
+    let scores = play(&[&tft, &all_d], GameLength::Rounds(10), noiseless, &mut rng).unwrap();
+    assert_abs_diff_eq!(scores[0], 0.9, epsilon = 1e-12);
+    assert_abs_diff_eq!(scores[1], 1.4, epsilon = 1e-12);
+    let scores = play(&[&all_d, &tft], GameLength::Rounds(1), noiseless, &mut rng).unwrap();
+    assert_eq!(scores, vec![5.0, 0.0]);
+
+    let scores = play(&[&tft, &all_d], GameLength::Continuation(0.5), noiseless, &mut rng).unwrap();
+    assert_abs_diff_eq!(scores[0], 0.5, epsilon = 1e-6);
+    assert_abs_diff_eq!(scores[1], 3.0, epsilon = 1e-6);
+
+    let scores = play(&[&tft, &all_d], GameLength::Infinite, noiseless, &mut rng).unwrap();
+    assert_abs_diff_eq!(scores[0], 1.0, epsilon = 1e-12);
+    assert_abs_diff_eq!(scores[1], 1.0, epsilon = 1e-12);
+
+    let wsls = Agent::new(vec![1, 0, 0, 1], vec![], 2, 2).unwrap();
+    let e = 0.1;
+    let noise = Noise { execution: e, perception: 0.0 };
+    let mixed = e * (1.0 - e);
+    let both_cooperate = (1.0 - e).powi(2) * (1.0 - 2.0 * mixed) + 2.0 * e * e * mixed;
+    let both_defect = 1.0 - both_cooperate - 2.0 * mixed;
+    // CC pays R, CD pays S = 0, DC pays T and DD pays P
+    let expected = 3.0 * both_cooperate + 5.0 * mixed + both_defect;
+    let scores = play(&[&wsls, &wsls], GameLength::Infinite, noise, &mut rng).unwrap();
+    assert_abs_diff_eq!(scores[0], expected, epsilon = 1e-6);
+    assert_abs_diff_eq!(scores[1], expected, epsilon = 1e-6);
+
+    for length in [GameLength::Rounds(0), GameLength::Continuation(1.0), GameLength::Continuation(-0.1)] {
+        assert!(matches!(play(&[&tft, &all_d], length, noiseless, &mut rng), Err(SimError::InvalidParameter(_))));
+    }
+}
+
+/// applies many random point, duplication and split mutations to agents of 2 and 3 actions, the genome must keep
+/// k^memory_len genes and the history must fit in the memory after every one of them. The 2 action agents of
+/// different memory lengths then play each other, and a duplicated agent plays like the agent it came from
+pub fn mutation_consistency_test(seed : u64) {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    let mut agents : Vec<Agent> = (0..20).map(|i| Agent::random_init_from_rng(i % 3, 2 + i % 2, &mut rng).unwrap()).collect();
+    for _ in 0..500 {
+        for agent in agents.iter_mut() {
+            // a duplication doubles the genome at least, the memory is kept below 6
+            let kind = match rng.gen_range(0..3) {
+                0 => MutationKind::Point,
+                1 if agent.genotype.memory_len < 6 => MutationKind::Duplication,
+                _ => MutationKind::Split,
+            };
+            agent.apply_mutation(kind, &mut rng);
+            assert!(agent.is_consistent(), "{:?} left an inconsistent agent {:?}", kind, agent);
+            assert_eq!(agent.genotype.id, agent.genotype.genome.to_id());
+        }
+    }
+
+    let game_board = GameBoard::prisoners_dilemma(5.0, 3.0, 1.0, 0.0).unwrap();
+    let two_actions : Vec<&Agent> = agents.iter().filter(|agent| agent.genotype.n_actions == 2).collect();
+    assert!(two_actions.iter().map(|agent| agent.genotype.memory_len).unique().count() > 1);
+    let noise = Noise { execution: 0.05, perception: 0.05 };
+    for (a, b) in two_actions.iter().tuple_combinations() {
+        let scores = iterated::play(&game_board, &[*a, *b], GameLength::Rounds(10), noise, &mut rng).unwrap();
+        assert_eq!(scores.len(), 2);
+
+        let mut duplicated = (*a).clone();
+        assert!(duplicated.apply_mutation(MutationKind::Duplication, &mut rng));
+        assert_eq!(
+            iterated::play(&game_board, &[&duplicated, *b], GameLength::Rounds(10), Noise::default(), &mut rng).unwrap(),
+            iterated::play(&game_board, &[*a, *b], GameLength::Rounds(10), Noise::default(), &mut rng).unwrap()
+        );
+    }
+
+    let mut game = Game::new(game_board, false, false).unwrap();
+    game.seed = seed;
+    game.p_p = 0.05;
+    game.p_d = 0.05;
+    game.p_r = 0.05;
+    game.run(5, two_actions.into_iter().cloned().collect()).unwrap();
+}
+
+/// TFT and its duplication are named in 2 player games only, with 3 players memory 1 sees the action of the last
+/// seat and not of an opponent, so neither the dataframe nor the json of the rounds names the genotypes
+pub fn strategy_name_test() {
+    let tft = Agent::new(vec![0, 1], vec![1], 1, 2).unwrap();
+    let duplicated_tft = Agent::new(vec![0, 1, 0, 1], vec![1], 2, 2).unwrap();
+    assert_eq!(tft.genotype.strategy(2), Some(Strategy::TitForTat));
+    assert_eq!(duplicated_tft.genotype.strategy(2), Some(Strategy::TitForTat));
+    assert_eq!(tft.genotype.strategy(3), None);
+
+    for n_players in [2, 3] {
+        let mut game = Game::new(GameBoard::new("prisoners_dilemma".to_string(), n_players).unwrap(), false, false).unwrap();
+        game.run(1, vec![tft.clone(); 10]).unwrap();
+        let expected = if n_players == 2 { Some("TFT") } else { None };
+
+        let rounds = game.round_state_to_dataframe().unwrap();
+        let strategies : Vec<Option<&str>> = rounds.column("strategy").unwrap().str().unwrap().into_iter().collect();
+        assert_eq!(strategies, vec![expected]);
+        let json = serde_json::to_value(&game.rounds[0]).unwrap();
+        assert_eq!(json["agent_data"]["01"]["strategy"].as_str(), expected);
+    }
+}
+
+/// under strong selection without mutations ALLD takes over a one move prisoner's dilemma from 30 ALLC and 10 ALLD
+/// agents with every selection rule, and the rule is written to the json of the game and read back
+pub fn selection_test(seed : u64) {
+    let all_c = Agent::new(vec![1], vec![], 0, 2).unwrap();
+    let all_d = Agent::new(vec![0], vec![], 0, 2).unwrap();
+    for selection in [
+        Selection::MoranBirthDeath(MoranBirthDeath { beta : 10.0 }),
+        Selection::MoranDeathBirth(MoranDeathBirth { beta : 10.0 }),
+        Selection::WrightFisher(WrightFisher { beta : 10.0 }),
+        Selection::Fermi(Fermi { beta : 10.0 }),
+    ] {
+        let game_board = GameBoard::prisoners_dilemma(5.0, 3.0, 1.0, 0.0).unwrap();
+        let mut game = Game::new(game_board, false, false).unwrap();
+        game.seed = seed;
+        game.p_p = 0.0;
+        game.p_d = 0.0;
+        game.p_r = 0.0;
+        game.selection = selection;
+        game.run(20, [vec![all_c.clone(); 30], vec![all_d.clone(); 10]].concat()).unwrap();
+
+        let state = game.rounds.last().unwrap();
+        assert_eq!(state.agent_data.get(&all_d.genotype).map(|data| data.count), Some(40), "{:?}", selection);
+        let loaded : Game = serde_json::from_str(&serde_json::to_string(&game).unwrap()).unwrap();
+        assert_eq!(loaded.selection, selection);
+    }
+}
+
+/// an ALLC population under duplications only: the duplicated genomes stay in the population, with merge_equivalent
+/// they are exported as the memory 0 ALLC genotype they came from and without it as genotypes of their own
+pub fn merge_equivalent_test(seed : u64) {
+    let all_c = Agent::new(vec![1], vec![], 0, 2).unwrap();
+    let run = |merge_equivalent : bool| {
+        let game_board = GameBoard::prisoners_dilemma(5.0, 3.0, 1.0, 0.0).unwrap();
+        let mut game = Game::new(game_board, false, false).unwrap();
+        game.seed = seed;
+        game.dynamics = PopulationDynamics::MeanField;
+        game.p_p = 0.0;
+        game.p_d = 0.05;
+        game.p_r = 0.0;
+        game.merge_equivalent = merge_equivalent;
+        game.run(10, vec![all_c.clone(); 100]).unwrap();
+        game
+    };
+
+    let merged = run(true);
+    let state = merged.rounds.last().unwrap();
+    assert_eq!(state.agent_data.len(), 1);
+    assert_abs_diff_eq!(state.agent_data[&all_c.genotype].population_share, 1.0, epsilon = 1e-12);
+    assert_abs_diff_eq!(state.metrics.shannon_entropy, 0.0);
+
+    let separate = run(false);
+    let state = separate.rounds.last().unwrap();
+    assert!(state.agent_data.keys().any(|genotype| genotype.memory_len > 0));
+    assert_eq!(
+        round_summary(&merged).len(), merged.rounds.len(),
+        "the merged export has one genotype per round"
+    );
+}
+
+/// with many mutations most genotypes die out again, the registry only keeps the ones of the last population
+/// while a genealogy keeps every genotype of its tree
+pub fn registry_prune_test(seed : u64) {
+    let run = |genealogy : Option<Genealogy>| {
+        let game_board = GameBoard::new("prisoners_dilemma".to_string(), 2).unwrap();
+        let mut game = Game::new(game_board, false, false).unwrap();
+        game.seed = seed;
+        game.p_p = 0.05;
+        game.genealogy = genealogy;
+        let agents = game.random_agents(200, 3).unwrap();
+        game.run(30, agents).unwrap();
+        game
+    };
+
+    let game = run(None);
+    let seen : HashSet<String> = game.rounds.iter()
+        .flat_map(|round_state| round_state.agent_data.keys().map(|genotype| genotype.id.clone()))
+        .collect();
+    assert!(game.registry.len() <= 200);
+    assert!(seen.len() > game.registry.len(), "no genotype died out");
+
+    let game = run(Some(Genealogy::new()));
+    assert_eq!(game.registry.len(), game.genealogy.as_ref().unwrap().len());
+}
+
+/// loads the game definitions shipped in games/ and checks that broken definitions written to dir are rejected:
+/// a missing profile, a payoff vector of the wrong length and a player count that does not match the keys
+pub fn game_file_test(dir : &str) {
+    let games_dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("games");
+    let prisoners_dilemma = GameBoard::from_file(games_dir.join("prisoners_dilemma.toml").to_str().unwrap()).unwrap();
+    assert_eq!(
+        prisoners_dilemma.payoff_matrix(),
+        GameBoard::prisoners_dilemma(5.0, 3.0, 1.0, 0.0).unwrap().payoff_matrix()
+    );
+    let stag_hunt = GameBoard::from_file(games_dir.join("stag_hunt.json").to_str().unwrap()).unwrap();
+    assert_eq!(stag_hunt.n_players, 2);
+    assert_eq!(stag_hunt.n_actions, 2);
+    assert_eq!(stag_hunt.get_payoff(&[1, 1]).unwrap(), &[4.0, 4.0]);
+    assert_eq!(stag_hunt.get_payoff(&[1, 0]).unwrap(), &[0.0, 3.0]);
+
+    let load = |file_name : &str, contents : &str| {
+        let path = std::path::Path::new(dir).join(file_name);
+        std::fs::write(&path, contents).unwrap();
+        GameBoard::from_file(path.to_str().unwrap())
+    };
+    let missing_profile = load("missing_profile.toml", r#"
+        name = "missing_profile"
+        n_players = 2
+        [payoff_matrix]
+        "11" = [3.0, 3.0]
+        "10" = [0.0, 5.0]
+        "01" = [5.0, 0.0]
+    "#);
+    assert!(matches!(missing_profile, Err(SimError::Serde(message)) if message.contains("Missing payoff")));
+
+    let wrong_length = load("wrong_length.json", r#"{
+        "name": "wrong_length", "n_players": 2,
+        "payoff_matrix": {"11": [3.0, 3.0], "10": [0.0, 5.0], "01": [5.0], "00": [1.0, 1.0]}
+    }"#);
+    assert!(matches!(wrong_length, Err(SimError::Serde(message)) if message.contains("Invalid payoff length")));
+
+    let wrong_players = load("wrong_players.json", r#"{
+        "name": "wrong_players", "n_players": 3,
+        "payoff_matrix": {"11": [3.0, 3.0], "10": [0.0, 5.0], "01": [5.0, 0.0], "00": [1.0, 1.0]}
+    }"#);
+    assert!(matches!(wrong_players, Err(SimError::InvalidPayoffTable(_))));
+}
+
+/// sizes that do not fit are errors instead of overflows or panics: k^m genes, k^n action profiles
+/// and alphabets too large for one base 36 digit per action
+pub fn oversized_test() {
+    assert!(matches!(Agent::new(vec![0, 1], vec![], 64, 2), Err(SimError::InvalidParameter(_))));
+    assert!(matches!(Agent::random_init(64), Err(SimError::InvalidParameter(_))));
+    assert!(matches!(Agent::random_init_with_actions(1, 37), Err(SimError::InvalidParameter(_))));
+    assert!(matches!(GameBoard::from_payoffs(HashMap::new(), 64, 2), Err(SimError::InvalidParameter(_))));
+    assert!(matches!(GameBoard::from_payoffs(HashMap::new(), 0, 2), Err(SimError::InvalidParameter(_))));
+}
+
+/// a genotype read from json has k^m genes and the id of its genome, whatever the file says
+pub fn genotype_json_test() {
+    let short = serde_json::from_str::<Genotype>(r#"{"id":"00","memory_len":5,"genome":[0,0]}"#);
+    assert!(short.unwrap_err().to_string().contains("Genome mismatch"));
+    let oversized = serde_json::from_str::<Genotype>(r#"{"id":"00","memory_len":64,"genome":[0,0]}"#);
+    assert!(oversized.is_err());
+
+    let stale = serde_json::from_str::<Genotype>(r#"{"id":"zzz","memory_len":1,"genome":[0,1]}"#).unwrap();
+    assert_eq!(stale.id, "01");
+    assert!(stale.is_consistent());
+    let tft = Agent::new(vec![0, 1], vec![1], 1, 2).unwrap();
+    assert_eq!(stale, tft.genotype);
+    let round_trip : Genotype = serde_json::from_str(&serde_json::to_string(&tft.genotype).unwrap()).unwrap();
+    assert_eq!(round_trip, tft.genotype);
+    assert_eq!(round_trip.id, tft.genotype.id);
+}
+
+/// a genealogy of TFT -> ALLC by a point mutation in round 3 -> the duplicated ALLC in round 5 is written as
+/// ((1111:2)11:3)01; and as 2 edges. The initial agents of a run hang from the tree at its first round whatever
+/// their birth round, and a node recorded by an earlier run that is younger than its child gets a branch of 0
+pub fn genealogy_test(seed : u64) {
+    let tft = Genotype::new(vec![0, 1], 1, 2).unwrap();
+    let all_c = Genotype::new(vec![1, 1], 1, 2).unwrap();
+    let duplicated = Genotype::new(vec![1, 1, 1, 1], 2, 2).unwrap();
+    let mut genealogy = Genealogy::new();
+    genealogy.add_root(&tft, 0);
+    genealogy.record(&tft, &all_c, &[MutationKind::Point], 3);
+    genealogy.record(&all_c, &duplicated, &[MutationKind::Duplication], 5);
+    assert_eq!(genealogy.to_newick(), "((1111:2)11:3)01;");
+
+    let edges = genealogy.edges_to_dataframe().unwrap();
+    assert_eq!(edges.height(), 2);
+    let children : Vec<&str> = edges.column("child_agent_id").unwrap().str().unwrap().into_no_null_iter().collect();
+    assert_eq!(children, vec!["11", "1111"]);
+    let mutations : Vec<&str> = edges.column("mutation").unwrap().str().unwrap().into_no_null_iter().collect();
+    assert_eq!(mutations, vec!["point", "duplication"]);
+    let rounds : Vec<u32> = edges.column("round_number").unwrap().u32().unwrap().into_no_null_iter().collect();
+    assert_eq!(rounds, vec![3, 5]);
+
+    let mut genealogy = Genealogy::new();
+    genealogy.add_root(&tft, 20);
+    genealogy.record(&tft, &all_c, &[MutationKind::Point], 3);
+    assert_eq!(genealogy.to_newick(), "(11:0)01;");
+
+    let mut game = Game::new(GameBoard::prisoners_dilemma(5.0, 3.0, 1.0, 0.0).unwrap(), false, false).unwrap();
+    game.seed = seed;
+    game.p_p = 0.05;
+    game.genealogy = Some(Genealogy::new());
+    let mut agents = game.random_agents(100, 2).unwrap();
+    for agent in agents.iter_mut() {
+        agent.genotype.birth_round = 50;
+    }
+    game.run(10, agents).unwrap();
+    let genealogy = game.genealogy.as_ref().unwrap();
+    assert!(genealogy.nodes().all(|node| node.round_number < 10));
+    assert!(genealogy.to_newick().ends_with(';'));
+    assert_eq!(
+        genealogy.edges_to_dataframe().unwrap().height(),
+        genealogy.nodes().filter(|node| node.parent_id.is_some()).count()
+    );
+}
+
+/// a 2 action table with the key [0, 2] would fill the slot of [1, 0], it is rejected instead
+pub fn invalid_payoff_table_test() {
+    let mut payoff_matrix : HashMap<Vec<Action>, Vec<f64>> = HashMap::new();
+    payoff_matrix.insert(vec![1, 1], vec![3.0, 3.0]);
+    payoff_matrix.insert(vec![1, 0], vec![0.0, 5.0]);
+    payoff_matrix.insert(vec![0, 1], vec![5.0, 0.0]);
+    payoff_matrix.insert(vec![0, 0], vec![1.0, 1.0]);
+    assert!(GameBoard::from_payoffs(payoff_matrix.clone(), 2, 2).is_ok());
+
+    payoff_matrix.insert(vec![0, 2], vec![9.0, 9.0]);
+    assert!(matches!(GameBoard::from_payoffs(payoff_matrix, 2, 2), Err(SimError::InvalidPayoffTable(_))));
+}
+
+/// runs the same seeded game on 1 and 4 threads and checks that every round is bit identical
+pub fn reproducibility_test(seed : u64) {
+    let run = |n_threads : usize| -> RoundSummary {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(n_threads).build().unwrap();
+        pool.install(|| {
+            let game_board = GameBoard::new("prisoners_dilemma".to_string(), 2).unwrap();
+            let mut game = Game::new(game_board, false, false).unwrap();
+            game.seed = seed;
+            game.p_p = 0.01;
+            let agents = game.random_agents(300, 2).unwrap();
+            game.run(20, agents).unwrap();
+            round_summary(&game)
+        })
+    };
+
+    assert_eq!(run(1), run(4));
+}
+
+/// runs a seeded game once without interruption and once resumed from its last checkpoint, written at round 24
+/// of 30 as 30 is not a multiple of the interval, so the resumed game plays the last 6 rounds itself.
+/// Both runs must produce bit identical rounds, and a sink attached to the resumed game gets the resumed rounds
+pub fn checkpoint_test(seed : u64, checkpoint_path : &str) {
+    let new_game = || {
+        let game_board = GameBoard::new("prisoners_dilemma".to_string(), 2).unwrap();
+        let mut game = Game::new(game_board, false, false).unwrap();
+        game.seed = seed;
+        game.p_p = 0.01;
+        game
+    };
+
+    let mut full = new_game();
+    let agents = full.random_agents(300, 2).unwrap();
+    full.run(30, agents).unwrap();
+
+    let mut interrupted = new_game();
+    interrupted.checkpoint_every = 12;
+    interrupted.checkpoint_path = Some(checkpoint_path.to_string());
+    let agents = interrupted.random_agents(300, 2).unwrap();
+    interrupted.run(30, agents).unwrap();
+    let mut checkpoint = Checkpoint::load(checkpoint_path).unwrap();
+    assert_eq!(checkpoint.next_round, 24);
+    assert_eq!(checkpoint.game.rounds.len(), 24);
+
+    let parquet_path = format!("{}.parquet", checkpoint_path);
+    checkpoint.game.stream_to_parquet(&parquet_path, 4).unwrap();
+    let resumed = Game::resume_checkpoint(checkpoint).unwrap();
+    assert_eq!(resumed.rounds.len(), 30);
+    assert_eq!(round_summary(&full), round_summary(&resumed));
+
+    let streamed = Game::read_from_parquet(parquet_path.clone()).unwrap();
+    let round_numbers : Vec<u32> = streamed.column("round_number").unwrap().u32().unwrap()
+        .into_no_null_iter().unique().sorted().collect();
+    assert_eq!(round_numbers, (24..30).collect::<Vec<u32>>());
+    let _ = std::fs::remove_file(parquet_path);
+}
+
+/// a run finishes and removes its sinks, a second run of the same game does not write to the finished sink
+/// and takes a new sink of its own
+pub fn sink_rerun_test(seed : u64, parquet_path : &str) {
+    let game_board = GameBoard::new("prisoners_dilemma".to_string(), 2).unwrap();
+    let mut game = Game::new(game_board, false, false).unwrap();
+    game.seed = seed;
+    let round_numbers = |path : &str| -> Vec<u32> {
+        Game::read_from_parquet(path.to_string()).unwrap().column("round_number").unwrap().u32().unwrap()
+            .into_no_null_iter().unique().sorted().collect()
+    };
+
+    game.stream_to_parquet(parquet_path, 2).unwrap();
+    game.run(3, game.random_agents(100, 2).unwrap()).unwrap();
+    assert!(game.sinks.is_empty());
+    game.run(2, game.random_agents(100, 2).unwrap()).unwrap();
+    assert_eq!(round_numbers(parquet_path), vec![0, 1, 2]);
+
+    let second_path = format!("{}.second", parquet_path);
+    game.stream_to_parquet(&second_path, 2).unwrap();
+    game.run(2, game.random_agents(100, 2).unwrap()).unwrap();
+    assert_eq!(round_numbers(&second_path), vec![0, 1]);
+    let _ = std::fs::remove_file(second_path);
+}
+
+/// plays the same seeded noisy rounds of a fixed population of n_genotypes memory 4 genotypes on 1 thread and
+/// on the default pool, the rounds must be bit identical, prints the time of both.
+/// The mean field dynamics without selection (d = 0) and without mutations keep every genotype in every round,
+/// so all the rounds are played at n_genotypes genotypes
+/// # Args:
+///     n_genotypes: usize - the number of distinct genotypes of the population, at most 2^16
+///     n_players: u32 - the group size, the number of groups grows as C(n_genotypes + n_players - 1, n_players)
+///     n_rounds: u32 - the number of rounds of each run
+pub fn play_round_benchmark(n_genotypes : usize, n_players : u32, n_rounds : u32) {
+    let run = |pool : rayon::ThreadPool| -> (RoundSummary, std::time::Duration) {
+        pool.install(|| {
+            let game_board = GameBoard::new("prisoners_dilemma".to_string(), n_players).unwrap();
+            let mut game = Game::new(game_board, false, false).unwrap();
+            game.seed = 7;
+            game.dynamics = PopulationDynamics::MeanField;
+            game.d = 0.0;
+            game.p_p = 0.0;
+            game.p_d = 0.0;
+            game.p_r = 0.0;
+            // noisy games are played again every round, noiseless ones would be cached after the first
+            game.execution_error = 0.05;
+            game.game_length = GameLength::Rounds(10);
+            let agents : Vec<Agent> = game.random_agents(4 * n_genotypes as u32, 4).unwrap().into_iter()
+                .unique_by(|agent| agent.genotype.clone())
+                .take(n_genotypes)
+                .collect();
+            assert_eq!(agents.len(), n_genotypes, "not enough distinct genotypes were drawn");
+
+            let start = std::time::Instant::now();
+            game.run(n_rounds, agents).unwrap();
+            let elapsed = start.elapsed();
+            assert!(game.rounds.iter().all(|round_state| round_state.agent_data.len() == n_genotypes));
+            (round_summary(&game), elapsed)
+        })
+    };
+
+    let (serial, serial_time) = run(rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+    let (parallel, parallel_time) = run(rayon::ThreadPoolBuilder::new().build().unwrap());
+    assert_eq!(serial, parallel);
+    println!(
+        "{} genotypes, {} players, {} rounds: {:?} on 1 thread, {:?} on {} threads",
+        n_genotypes, n_players, n_rounds, serial_time, parallel_time, rayon::current_num_threads()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_two_players() {
+        run_test(2);
+    }
+
+    #[test]
+    fn run_three_players() {
+        run_test(3);
+    }
+
+    #[test]
+    fn self_play() {
+        self_play_test();
+    }
+
+    #[test]
+    fn group_game() {
+        group_game_test();
+    }
+
+    #[test]
+    fn asymmetric_game() {
+        asymmetric_game_test();
+    }
+
+    #[test]
+    fn iterated_game() {
+        iterated_game_test();
+    }
+
+    #[test]
+    fn mutation_consistency() {
+        mutation_consistency_test(9);
+    }
+
+    #[test]
+    fn strategy_name() {
+        strategy_name_test();
+    }
+
+    #[test]
+    fn selection() {
+        selection_test(11);
+    }
+
+    #[test]
+    fn game_file() {
+        let dir = std::env::temp_dir().join(format!("evolutionary_sim_games_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        game_file_test(dir.to_str().unwrap());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn oversized() {
+        oversized_test();
+    }
+
+    #[test]
+    fn genotype_json() {
+        genotype_json_test();
+    }
+
+    #[test]
+    fn genealogy() {
+        genealogy_test(13);
+    }
+
+    #[test]
+    fn invalid_payoff_table() {
+        invalid_payoff_table_test();
+    }
+
+    #[test]
+    fn merge_equivalent() {
+        merge_equivalent_test(3);
+    }
+
+    #[test]
+    fn registry_prune() {
+        registry_prune_test(5);
+    }
+
+    #[test]
+    fn play_round_parallel() {
+        play_round_benchmark(100, 2, 2);
+    }
+
+    #[test]
+    fn reproducibility() {
+        reproducibility_test(42);
+    }
+
+    #[test]
+    fn sink_rerun() {
+        let path = std::env::temp_dir().join(format!("evolutionary_sim_sink_{}.parquet", std::process::id()));
+        let path = path.to_str().unwrap();
+        sink_rerun_test(42, path);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn checkpoint() {
+        let path = std::env::temp_dir().join(format!("evolutionary_sim_checkpoint_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        checkpoint_test(42, path);
+        let _ = std::fs::remove_file(path);
+    }
+}
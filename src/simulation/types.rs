@@ -1,17 +1,57 @@
-use polars::frame::DataFrame;
 use serde::{Serialize, Deserialize, Deserializer};
-use serde::ser::{SerializeMap, Serializer, SerializeStruct};
+use serde::ser::{self, SerializeMap, Serializer, SerializeStruct};
 use serde::de::{self, Visitor, MapAccess};
 use serde_json::Value;
 use std::fmt;
-use crate::simulation::agent::Agent;
+use crate::simulation::genotype::Genotype;
+use crate::simulation::error::SimError;
+use crate::simulation::games;
 use std::collections::HashMap;
+use std::path::Path;
+
+/// an action is an index into the action alphabet of size n_actions,
+/// for the 2 action games 1 is cooperate and 0 is defect
+pub type Action = u8;
+pub const COOPERATE : Action = 1;
+pub const DEFECT : Action = 0;
+/// the largest action alphabet, every action is written as one base 36 digit in the ids and the payoff keys
+pub const MAX_ACTIONS : usize = 36;
+
+const DIGITS : &[u8; MAX_ACTIONS] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+/// the base 36 digit of an action, genotypes and boards are only built with actions below MAX_ACTIONS
+pub fn action_to_digit(action : Action) -> char {
+    DIGITS[action as usize % MAX_ACTIONS] as char
+}
+
+/// writes an action profile as a key like "10", one base 36 digit per player
+pub fn actions_to_key(actions : &[Action]) -> Result<String, SimError> {
+    actions.iter()
+        .map(|&a| std::char::from_digit(a as u32, 36).ok_or_else(|| SimError::InvalidPayoffTable(
+            format!("Invalid action {} does not fit in a base 36 digit", a)
+        )))
+        .collect()
+}
+
+/// parses a key written by actions_to_key
+pub fn key_to_actions(key : &str) -> Result<Vec<Action>, SimError> {
+    key.chars()
+        .map(|c| match c.to_digit(36) {
+            Some(d) => Ok(d as Action),
+            None => Err(SimError::InvalidPayoffTable(
+                format!("Invalid character in key, expected a base 36 digit got {:?}", c)
+            )),
+        })
+        .collect()
+}
 
-use super::game::Game;
 #[derive(Debug, Clone)]
 pub struct GameBoard {
-    payoff_matrix: HashMap<Vec<bool>, Vec<f64>>,
+    payoff_matrix: HashMap<Vec<Action>, Vec<f64>>,
+    // the payoff table compiled into one array, the n payoffs of profile p start at p * n
+    payoffs: Vec<f64>,
     pub n_players: u32,
+    pub n_actions: u32,
 }
 
 impl Serialize for GameBoard {
@@ -21,16 +61,7 @@ impl Serialize for GameBoard {
     {
         let mut map = serializer.serialize_map(Some(self.payoff_matrix.len()))?;
         for (k, v) in &self.payoff_matrix {
-            let key_as_strings: Vec<String> = k.iter().
-                map(|&b| 
-                    if b == true {
-                        "1".to_string()
-                    } else {
-                        "0".to_string()
-                    }
-                ).collect();
-            let key_as_string = key_as_strings.join("");
-            println!("key as strings: {:?}", key_as_string);
+            let key_as_string = actions_to_key(k).map_err(ser::Error::custom)?;
             map.serialize_entry(&key_as_string, v)?;
         }
         map.end()
@@ -47,95 +78,269 @@ impl<'de> Deserialize<'de> for GameBoard {
 
         // Attempt to convert the Value into the expected HashMap format
         if let Value::Object(map) = v {
-            let mut payoff_matrix: HashMap<Vec<bool>, Vec<f64>> = HashMap::new();
+            let mut payoff_matrix: HashMap<Vec<Action>, Vec<f64>> = HashMap::new();
 
             for (key, value) in map {
-                println!("key: {:?}, value: {:?}", key, value);
-                let key_as_bools: Vec<bool> = key.chars()
-                    .map(|c| match c {
-                        '1' => true,
-                        '0' => false,
-                        _ => panic!("Invalid character in key, expected '1' or '0' got {:?}", c),
-                    })
-                    .collect();
+                let key_as_actions: Vec<Action> = key_to_actions(&key).map_err(de::Error::custom)?;
 
                 if let Value::Array(nums) = value {
                     let nums: Vec<f64> = nums.into_iter().map(|n| 
-                        n.as_f64().expect("Expected a floating point number")
-                    ).collect();
+                        n.as_f64().ok_or_else(|| de::Error::custom(format!("Expected a floating point number got {}", n)))
+                    ).collect::<Result<Vec<f64>, D::Error>>()?;
 
-                    payoff_matrix.insert(key_as_bools, nums);
+                    payoff_matrix.insert(key_as_actions, nums);
                 } else {
-                    panic!("Expected an array of numbers");
+                    return Err(de::Error::custom(format!("Expected an array of numbers for key {:?}", key)));
                 }
             }
 
-            let n_players = payoff_matrix.keys().next().unwrap().len() as u32; // Assuming all keys have the same length
-            Ok(GameBoard {
-                payoff_matrix : payoff_matrix,
-                n_players: n_players, // Assuming all keys have the same length
-            })
+            let n_players = payoff_matrix.keys().next()
+                .ok_or_else(|| de::Error::custom("Expected at least one action profile in the payoff table"))?
+                .len() as u32; // Assuming all keys have the same length
+            // the alphabet is the largest action used, games always have at least 2 actions
+            let n_actions = payoff_matrix.keys().flatten().max().map_or(2, |&a| (a as u32 + 1).max(2));
+            let mut board = GameBoard {
+                payoff_matrix,
+                payoffs: Vec::new(),
+                n_players, // Assuming all keys have the same length
+                n_actions,
+            };
+            board.compile().map_err(de::Error::custom)?;
+            Ok(board)
         } else {
-            panic!("Expected a JSON object");
+            Err(de::Error::custom("Expected a JSON object"))
         }
     }
 }
 
 impl GameBoard {
-    pub fn new(game_name: String, n_players : u32) -> Result<GameBoard, String> {
+    /// builds one of the built in games with its default parameters,
+    /// see games.rs for the parametric versions and default_params for the values used.
+    /// The parametric prisoners_dilemma is a 2 player game, for 3 players the original hand written table is used
+    pub fn new(game_name: String, n_players : u32) -> Result<GameBoard, SimError> {
         
-        let mut payoff_matrix : HashMap<Vec<bool>, Vec<f64>> = HashMap::new();
-        match game_name.as_str() {
-            "prisoners_dilemma" => {
-                match n_players  {
-                2 => {
-                    payoff_matrix.insert(vec![true, true], vec![5.0, 10.0]); // agent 1 cooperates, agent 2 cooperates
-                    payoff_matrix.insert(vec![true, false], vec![1.0, 15.0]); // agent 1 cooperates, agent 2 defects
-                    payoff_matrix.insert(vec![false, true], vec![10.0, 10.0]); // agent 1 defects, agent 2 cooperates
-                    payoff_matrix.insert(vec![false, false], vec![0.0, 5.0]);  // agent 1 defects, agent 2 defects
-                }, 
-                3 => {
-                    // All cooperate
-                    payoff_matrix.insert(vec![true, true, true], vec![5.0, 5.0, 5.0]);
-                    // Two cooperate, one defects
-                    payoff_matrix.insert(vec![true, true, false], vec![3.0, 3.0, 10.0]);
-                    payoff_matrix.insert(vec![true, false, true], vec![3.0, 10.0, 3.0]);
-                    payoff_matrix.insert(vec![false, true, true], vec![10.0, 3.0, 3.0]);
-                    // One cooperates, two defect
-                    payoff_matrix.insert(vec![true, false, false], vec![1.0, 0.0, 0.0]);
-                    payoff_matrix.insert(vec![false, true, false], vec![0.0, 1.0, 0.0]);
-                    payoff_matrix.insert(vec![false, false, true], vec![0.0, 0.0, 1.0]);
-                    // All defect
-                    payoff_matrix.insert(vec![false, false, false], vec![0.0, 0.0, 0.0]);
-                }
+        const C : Action = COOPERATE;
+        const D : Action = DEFECT;
+        if game_name == "prisoners_dilemma" && n_players == 3 {
+            let mut payoff_matrix : HashMap<Vec<Action>, Vec<f64>> = HashMap::new();
+            // All cooperate
+            payoff_matrix.insert(vec![C, C, C], vec![5.0, 5.0, 5.0]);
+            // Two cooperate, one defects
+            payoff_matrix.insert(vec![C, C, D], vec![3.0, 3.0, 10.0]);
+            payoff_matrix.insert(vec![C, D, C], vec![3.0, 10.0, 3.0]);
+            payoff_matrix.insert(vec![D, C, C], vec![10.0, 3.0, 3.0]);
+            // One cooperates, two defect
+            payoff_matrix.insert(vec![C, D, D], vec![1.0, 0.0, 0.0]);
+            payoff_matrix.insert(vec![D, C, D], vec![0.0, 1.0, 0.0]);
+            payoff_matrix.insert(vec![D, D, C], vec![0.0, 0.0, 1.0]);
+            // All defect
+            payoff_matrix.insert(vec![D, D, D], vec![0.0, 0.0, 0.0]);
+            return GameBoard::from_payoffs(payoff_matrix, n_players, 2);
+        }
 
-                _ => return Err(format!("Invalid number of players: {} not implemted yet", n_players)),
-            };
-            },
-            _ => return Err(format!("Invalid game name: {} not implemted yet", game_name)),
+        let params = games::default_params(&game_name, n_players)
+            .ok_or_else(|| SimError::InvalidParameter(format!("Invalid game name: {} not implemted yet", game_name)))?;
+        GameBoard::from_params(&game_name, n_players, &params)
+    }
+
+    /// builds a game board from an explicit payoff table
+    /// # Args:
+    ///     payoff_matrix: HashMap<Vec<Action>, Vec<f64>> - maps each action profile to the payoff of each player
+    ///     n_players: u32 - the number of players in one interaction
+    ///     n_actions: u32 - the size of the action alphabet
+    pub fn from_payoffs(
+        payoff_matrix : HashMap<Vec<Action>, Vec<f64>>, n_players : u32, n_actions : u32
+    ) -> Result<GameBoard, SimError> {
+        let mut board = GameBoard {
+            payoff_matrix,
+            payoffs: Vec::new(),
+            n_players,
+            n_actions,
+        };
+        board.compile()?;
+        Ok(board)
+    }
+
+    /// loads a game from a game definition file, the format is picked from the file extension
+    /// # Args:
+    ///     file_path: &str - path to a .json or .toml file holding a GameDefinition
+    /// # Returns:
+    ///     Result<GameBoard, SimError> - the board if the payoff table is complete
+    pub fn from_file(file_path : &str) -> Result<GameBoard, SimError> {
+        let contents = std::fs::read_to_string(file_path)?;
+
+        let definition : GameDefinition = match Path::new(file_path).extension().and_then(|ext| ext.to_str()) {
+            Some("json") => serde_json::from_str(&contents)?,
+            Some("toml") => toml::from_str(&contents)?,
+            _ => return Err(SimError::InvalidParameter(
+                format!("Unsupported game definition format: {} expected .json or .toml", file_path)
+            )),
+        };
+
+        let mut board = definition.payoff_matrix;
+        if board.n_players != definition.n_players {
+            return Err(SimError::InvalidPayoffTable(format!(
+                "Game {} declares {} players but the payoff table is keyed by {} actions",
+                definition.name, definition.n_players, board.n_players
+            )));
         }
-        
-        Ok(GameBoard {
-            payoff_matrix: payoff_matrix,
-            n_players: n_players,
+        if let Some(n_actions) = definition.n_actions {
+            if n_actions < board.n_actions {
+                return Err(SimError::InvalidPayoffTable(format!(
+                    "Game {} declares {} actions but the payoff table uses {}",
+                    definition.name, n_actions, board.n_actions
+                )));
+            }
+            board.n_actions = n_actions;
+        }
+        board.compile()?;
+        Ok(board)
+    }
+
+    /// checks that all k^n action profiles are present, that every action is below k
+    /// and that every payoff vector has length n
+    pub fn validate(&self) -> Result<(), SimError> {
+        let n_players = self.n_players as usize;
+        if self.n_actions < 2 || self.n_actions as usize > MAX_ACTIONS {
+            return Err(SimError::InvalidParameter(
+                format!("Invalid number of actions: {} expected 2..={}", self.n_actions, MAX_ACTIONS)
+            ));
+        }
+        if n_players == 0 {
+            return Err(SimError::InvalidParameter("Invalid number of players: 0 expected at least 1".to_string()));
+        }
+        for (action, payoff) in &self.payoff_matrix {
+            if action.len() != n_players {
+                return Err(SimError::InvalidPayoffTable(
+                    format!("Invalid action length: {} expected {}", action.len(), n_players)
+                ));
+            }
+            // an action outside the alphabet would land on the slot of another profile when compiled
+            if let Some(&a) = action.iter().find(|&&a| a as u32 >= self.n_actions) {
+                return Err(SimError::InvalidPayoffTable(
+                    format!("Invalid action {} in profile {:?} expected an action below {}", a, action, self.n_actions)
+                ));
+            }
+            if payoff.len() != n_players {
+                return Err(SimError::InvalidPayoffTable(format!(
+                    "Invalid payoff length for action {:?}: {} expected {}", action, payoff.len(), n_players
+                )));
+            }
+        }
+
+        for profile in 0..self.n_profiles()? {
+            let action = self.profile_to_actions(profile);
+            if !self.payoff_matrix.contains_key(&action) {
+                return Err(SimError::InvalidPayoffTable(format!("Missing payoff for action profile {:?}", action)));
+            }
+        }
+        Ok(())
+    }
+
+    /// validates the payoff table and lays it out in payoffs by profile index
+    fn compile(&mut self) -> Result<(), SimError> {
+        self.validate()?;
+        let n_players = self.n_players as usize;
+        let mut payoffs = vec![0.0; self.n_profiles()? * n_players];
+        for (action, payoff) in &self.payoff_matrix {
+            let start = self.profile_index(action) * n_players;
+            payoffs[start..start + n_players].copy_from_slice(payoff);
+        }
+        self.payoffs = payoffs;
+        Ok(())
+    }
+
+    /// a copy of the payoff table keyed by action profile
+    pub fn payoff_matrix(&self) -> HashMap<Vec<Action>, Vec<f64>> {
+        self.payoff_matrix.clone()
+    }
+
+    /// true if the payoffs do not depend on the seats, swapping the actions of two players swaps their payoffs.
+    /// Swaps of neighbouring seats generate every seating so only those are checked
+    pub fn is_symmetric(&self) -> bool {
+        let n_players = self.n_players as usize;
+        (0..self.payoffs.len() / n_players).all(|profile| {
+            let action = self.profile_to_actions(profile);
+            let payoff = &self.payoffs[profile * n_players..(profile + 1) * n_players];
+            (0..n_players.saturating_sub(1)).all(|seat| {
+                let mut swapped = action.clone();
+                swapped.swap(seat, seat + 1);
+                let start = self.profile_index(&swapped) * n_players;
+                let mut swapped_payoff = self.payoffs[start..start + n_players].to_vec();
+                swapped_payoff.swap(seat, seat + 1);
+                swapped_payoff == payoff
+            })
         })
     }
 
-    pub fn get_payoff(&self, action: &Vec<bool>) -> Vec<f64> {
+    /// the number of action profiles k^n, an error when the table would not fit in memory
+    pub fn n_profiles(&self) -> Result<usize, SimError> {
+        (self.n_actions as usize).checked_pow(self.n_players)
+            .ok_or_else(|| SimError::InvalidParameter(format!(
+                "Invalid game size: {}^{} action profiles do not fit in memory", self.n_actions, self.n_players
+            )))
+    }
 
-        if action.len() != self.n_players as usize {
-            panic!("Invalid action length: {} expected {}", action.len(), self.n_players);
+    /// decodes an action profile index in base k, the first player is the most significant digit
+    /// matching the string keys
+    pub fn profile_to_actions(&self, profile : usize) -> Vec<Action> {
+        let k = self.n_actions as usize;
+        let mut action = vec![0; self.n_players as usize];
+        let mut rest = profile;
+        for slot in action.iter_mut().rev() {
+            *slot = (rest % k) as Action;
+            rest /= k;
         }
+        action
+    }
+
+    /// encodes an action profile as its index in base k, the inverse of profile_to_actions
+    pub fn profile_index(&self, action : &[Action]) -> usize {
+        let k = self.n_actions as usize;
+        action.iter().fold(0, |acc, &a| acc * k + a as usize)
+    }
+
+    /// the payoff of every player for the profile index of profile_index, without hashing or copying
+    /// # Returns:
+    ///     Result<&[f64], SimError> - payoff[i] is the payoff of player i, an error if the index is out of the table
+    pub fn payoff(&self, profile : usize) -> Result<&[f64], SimError> {
+        let n_players = self.n_players as usize;
+        self.payoffs.get(profile * n_players..(profile + 1) * n_players)
+            .ok_or_else(|| SimError::InvalidPayoffTable(format!("Invalid action profile index: {}", profile)))
+    }
+
+    pub fn get_payoff(&self, action: &[Action]) -> Result<&[f64], SimError> {
 
-        match self.payoff_matrix.get(action) {
-            Some(payoff) => payoff.clone(),
-            None => panic!("Invalid action: {:?}", action),
+        if action.len() != self.n_players as usize {
+            return Err(SimError::InvalidPayoffTable(
+                format!("Invalid action length: {} expected {}", action.len(), self.n_players)
+            ));
+        }
+        if action.iter().any(|&a| a as u32 >= self.n_actions) {
+            return Err(SimError::InvalidPayoffTable(format!("Invalid action: {:?}", action)));
         }
+
+        self.payoff(self.profile_index(action))
     }
 
 
 }
 
+/// the on disk format read by GameBoard::from_file
+/// # Variables:
+///     pub name: String - the name of the game
+///     pub n_players: u32 - the number of players in one interaction
+///     pub n_actions: Option<u32> - the size of the action alphabet, inferred from the keys when missing
+///     pub payoff_matrix: GameBoard - maps action profiles like "10" to the payoff of each player
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GameDefinition {
+    pub name : String,
+    pub n_players : u32,
+    #[serde(default)]
+    pub n_actions : Option<u32>,
+    pub payoff_matrix : GameBoard,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AgentRoundData {
     pub count : u32, 
@@ -147,19 +352,31 @@ pub struct AgentRoundData {
 impl AgentRoundData {
     pub fn from(count: u32, score : f64, fitness : f64, population_share : f64) -> AgentRoundData {
         AgentRoundData {
-            count: count,
-            score: score,
-            fitness: fitness,
-            population_share: population_share
+            count,
+            score,
+            fitness,
+            population_share
+        }
+    }
+
+    /// adds the agents of other to this genotype, the score and the fitness become the means weighted by
+    /// the population shares
+    pub fn merge(&mut self, other : &AgentRoundData) {
+        let population_share = self.population_share + other.population_share;
+        if population_share > 0.0 {
+            self.score = (self.score * self.population_share + other.score * other.population_share) / population_share;
+            self.fitness = (self.fitness * self.population_share + other.fitness * other.population_share) / population_share;
         }
+        self.count += other.count;
+        self.population_share = population_share;
     }
 }
 
 ///this holds features like count and population share which are useful to have precomputed for each round
-/// it is used in the hashmap with agents IE HashMap<Agent, AgentMetaData>
+/// it is used in the population maps IE HashMap<Genotype, AgentMetaData>
 /// # Variables:
-///    pub count: u32 - the number of agents with the same genome
-///     pub population_share: f64 - the share of the population with the same genome
+///    pub count: u32 - the number of agents with the genotype
+///     pub population_share: f64 - the share of the population with the genotype
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AgentMetaData {
     pub count : u32,
@@ -175,30 +392,87 @@ impl AgentMetaData {
     }
 }
 
+impl Default for AgentMetaData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct SerializationAgentData {
-    agent_data : Agent,
-    round_data : AgentRoundData
+    agent_data : Genotype,
+    round_data : AgentRoundData,
+    // written for the readers of the json, it is derived from the genome and the group size when reading
+    #[serde(default)]
+    strategy : Option<String>,
 }
 
 impl SerializationAgentData {
-    fn new(agent_data : Agent, round_data : AgentRoundData) -> SerializationAgentData {
+    fn new(agent_data : Genotype, round_data : AgentRoundData, n_players : u32) -> SerializationAgentData {
         SerializationAgentData {
-            agent_data: agent_data,
-            round_data: round_data
+            strategy: agent_data.strategy(n_players).map(|strategy| strategy.name().to_string()),
+            agent_data,
+            round_data,
         }
     }
 }
 
 
+/// diversity of the population of a round, computed from the population shares p_i of the genotypes
+/// # Variables:
+///     pub shannon_entropy: f64 - H = -sum_i p_i ln p_i
+///     pub simpson_diversity: f64 - 1 - sum_i p_i^2, the probability that two random agents differ
+///     pub effective_number: f64 - exp(H), the number of equally common genotypes with the same entropy
+///     pub dominant_share: f64 - the largest p_i
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct DiversityMetrics {
+    pub shannon_entropy : f64,
+    pub simpson_diversity : f64,
+    pub effective_number : f64,
+    pub dominant_share : f64,
+}
+
+impl DiversityMetrics {
+    /// the shares should sum to 1 and be given in a fixed order so the sums are reproducible
+    pub fn from_shares<I: IntoIterator<Item = f64>>(shares : I) -> DiversityMetrics {
+        let mut shannon_entropy = 0.0;
+        let mut sum_of_squares = 0.0;
+        let mut dominant_share : f64 = 0.0;
+        for share in shares {
+            if share > 0.0 {
+                shannon_entropy -= share * share.ln();
+            }
+            sum_of_squares += share * share;
+            dominant_share = dominant_share.max(share);
+        }
+        DiversityMetrics {
+            shannon_entropy,
+            simpson_diversity: 1.0 - sum_of_squares,
+            effective_number: shannon_entropy.exp(),
+            dominant_share,
+        }
+    }
+
+    /// the metrics of a population map, iterated in genotype order
+    pub fn from_population(agents : &HashMap<Genotype, AgentMetaData>) -> DiversityMetrics {
+        let mut sorted : Vec<(&Genotype, &AgentMetaData)> = agents.iter().collect();
+        sorted.sort_by(|a, b| a.0.cmp(b.0));
+        DiversityMetrics::from_shares(sorted.into_iter().map(|(_, metadata)| metadata.population_share))
+    }
+}
+
 /// This holds the state of the game at a given round
 /// # Variables:
 ///     pub round_number: u32 - the round number
-///     pub agent_data: HashMap<Agent, AgentRoundData> - the data for each agent in the round
+///     pub n_players: u32 - the group size of the game, the strategies are only named for 2 players
+///     pub agent_data: HashMap<Genotype, AgentRoundData> - the data for each genotype in the round
+///     pub metrics: DiversityMetrics - the diversity of the population that played the round
 #[derive(Debug, Clone)]
 pub struct RoundState {
     pub round_number : u32,
-    pub agent_data : HashMap<Agent, AgentRoundData>,
+    pub n_players : u32,
+    pub agent_data : HashMap<Genotype, AgentRoundData>,
+    pub metrics : DiversityMetrics,
 }
 
 impl Serialize for RoundState {
@@ -206,15 +480,17 @@ impl Serialize for RoundState {
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("RoundState", 2)?;
+        let mut state = serializer.serialize_struct("RoundState", 4)?;
         state.serialize_field("round_number", &self.round_number)?;
-        
+        state.serialize_field("n_players", &self.n_players)?;
+
         // Serialize agent_data with agent.id as the key
         let agent_data_map = self.agent_data.iter().
             map(|(agent, data)| 
-            (&agent.id, SerializationAgentData::new(agent.clone(), data.clone()
-        )));
+            (&agent.id, SerializationAgentData::new(agent.clone(), data.clone(), self.n_players)
+        ));
         state.serialize_field("agent_data", &agent_data_map.collect::<HashMap<_, _>>())?;
+        state.serialize_field("metrics", &self.metrics)?;
         
         state.end()
     }
@@ -227,7 +503,7 @@ impl<'de> Deserialize<'de> for RoundState {
     {
         #[derive(Deserialize)]
         #[serde(field_identifier, rename_all = "snake_case")]
-        enum Field { RoundNumber, AgentData }
+        enum Field { RoundNumber, NPlayers, AgentData, Metrics }
 
         struct RoundStateVisitor;
 
@@ -243,7 +519,9 @@ impl<'de> Deserialize<'de> for RoundState {
                 V: MapAccess<'de>,
             {
                 let mut round_number = None;
+                let mut n_players = None;
                 let mut agent_data = None;
+                let mut metrics = None;
                 while let Some(key) = map.next_key()? {
                     match key {
                         Field::RoundNumber => {
@@ -252,37 +530,59 @@ impl<'de> Deserialize<'de> for RoundState {
                             }
                             round_number = Some(map.next_value()?);
                         },
+                        Field::NPlayers => {
+                            if n_players.is_some() {
+                                return Err(de::Error::duplicate_field("n_players"));
+                            }
+                            n_players = Some(map.next_value()?);
+                        },
                         Field::AgentData => {
                             if agent_data.is_some() {
                                 return Err(de::Error::duplicate_field("agent_data"));
                             }
                             // Deserialize into a temporary structure that mirrors the JSON
                             let temp_agent_data: HashMap<String, SerializationAgentData> = map.next_value()?;
-                            // Transform into the expected HashMap<Agent, AgentRoundData>
-                            agent_data = Some(temp_agent_data.into_iter().map(|(id, data)| {
-                                // Assuming Agent can be constructed from its ID
-                                let agent: Agent = data.agent_data;
-                                (agent, data.round_data)
+                            // Transform into the expected HashMap<Genotype, AgentRoundData>
+                            agent_data = Some(temp_agent_data.into_values().map(|data| {
+                                // older files stored whole agents, their histories are ignored
+                                let genotype: Genotype = data.agent_data;
+                                (genotype, data.round_data)
                             }).collect());
                         },
+                        Field::Metrics => {
+                            if metrics.is_some() {
+                                return Err(de::Error::duplicate_field("metrics"));
+                            }
+                            metrics = Some(map.next_value()?);
+                        },
                     }
                 }
                 let round_number = round_number.ok_or_else(|| de::Error::missing_field("round_number"))?;
-                let agent_data: HashMap<Agent, AgentRoundData> = agent_data.ok_or_else(|| de::Error::missing_field("agent_data"))?;
-                Ok(RoundState { round_number, agent_data })
+                let agent_data: HashMap<Genotype, AgentRoundData> = agent_data.ok_or_else(|| de::Error::missing_field("agent_data"))?;
+                // older files have no metrics, they are recomputed from the population shares
+                let metrics = metrics.unwrap_or_else(|| {
+                    let mut sorted : Vec<(&Genotype, &AgentRoundData)> = agent_data.iter().collect();
+                    sorted.sort_by(|a, b| a.0.cmp(b.0));
+                    DiversityMetrics::from_shares(sorted.into_iter().map(|(_, data)| data.population_share))
+                });
+                // older files do not record the group size, their rounds are read as 2 player rounds
+                let n_players = n_players.unwrap_or(2);
+                Ok(RoundState { round_number, n_players, agent_data, metrics })
             }
         }
 
-        const FIELDS: &'static [&'static str] = &["round_number", "agent_data"];
+        const FIELDS: &[&str] = &["round_number", "n_players", "agent_data", "metrics"];
         deserializer.deserialize_struct("RoundState", FIELDS, RoundStateVisitor)
     }
 }
 
 impl RoundState {
-    pub fn new(round_number: u32) -> RoundState {
+    pub fn new(round_number: u32, n_players : u32) -> RoundState {
         RoundState {
-            round_number: round_number,
+            round_number,
+            n_players,
             agent_data: HashMap::new(),
+            metrics: DiversityMetrics::default(),
         }
     }
 }
\ No newline at end of file
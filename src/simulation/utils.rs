@@ -1,14 +1,13 @@
-use crate::simulation::types::RoundState;
-use polars::prelude::*;
 use serde_json::{self, Value};
+use crate::simulation::error::SimError;
 
-pub fn read_json<T>(filename: &str) -> Result<T, serde_json::Error> 
+pub fn read_json<T>(filename: &str) -> Result<T, SimError> 
 where
     T: for<'de> serde::de::Deserialize<'de>, // Add this trait bound
 {
-    let contents = std::fs::read_to_string(filename).expect("Failed to read file");
+    let contents = std::fs::read_to_string(filename)?;
     let json: Value = serde_json::from_str(&contents)?;
-    convert_value(json)
+    Ok(convert_value(json)?)
 }
 
 fn convert_value<T>(value: Value) -> Result<T, serde_json::Error>
@@ -17,7 +16,3 @@ where
 {
     serde_json::from_value(value)
 }
-pub fn write_to_parquet(filename : &str){
-
-}
-